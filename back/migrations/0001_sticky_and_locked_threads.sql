@@ -0,0 +1,8 @@
+ALTER TABLE threads
+    ADD COLUMN sticky BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN locked BOOLEAN NOT NULL DEFAULT false;
+
+-- identities (sid cookie values) allowed to use the staff endpoints
+CREATE TABLE staff (
+    identity TEXT PRIMARY KEY
+);
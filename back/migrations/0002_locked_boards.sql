@@ -0,0 +1,2 @@
+-- a locked board is read-only: no new threads and no replies
+ALTER TABLE boards ADD COLUMN locked BOOLEAN NOT NULL DEFAULT false;
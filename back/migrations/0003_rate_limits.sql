@@ -0,0 +1,8 @@
+-- token buckets for the postgres rate limiter store, losing them on a crash is fine
+CREATE UNLOGGED TABLE rate_limits (
+    key TEXT NOT NULL,
+    action TEXT NOT NULL,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (key, action)
+);
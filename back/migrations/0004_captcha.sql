@@ -0,0 +1,14 @@
+-- never | always | threads | new_identities
+ALTER TABLE boards
+    ADD COLUMN captcha TEXT NOT NULL DEFAULT 'never'
+        CHECK (captcha IN ('never', 'always', 'threads', 'new_identities')),
+    -- in minutes, only used by the new_identities mode
+    ADD COLUMN captcha_identity_age INTEGER NOT NULL DEFAULT 60;
+
+-- one pending captcha per identity, requesting a new one replaces the old
+CREATE TABLE captchas (
+    identity TEXT PRIMARY KEY,
+    answer BYTEA NOT NULL,
+    expires TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX captchas_expires_idx ON captchas (expires);
@@ -0,0 +1,2 @@
+-- leading zero bits a proof of work needs to stand in for a captcha, 0 turns it off
+ALTER TABLE boards ADD COLUMN pow_difficulty INTEGER NOT NULL DEFAULT 0;
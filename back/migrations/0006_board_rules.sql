@@ -0,0 +1,29 @@
+CREATE TABLE bans (
+    id SERIAL PRIMARY KEY,
+    identity TEXT NOT NULL,
+    -- NULL bans from every board
+    board TEXT REFERENCES boards (code) ON DELETE CASCADE,
+    reason TEXT NOT NULL DEFAULT '',
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- NULL never expires
+    expires TIMESTAMPTZ
+);
+CREATE INDEX bans_identity_idx ON bans (identity);
+
+CREATE TABLE board_rules (
+    id SERIAL PRIMARY KEY,
+    board TEXT NOT NULL REFERENCES boards (code) ON DELETE CASCADE,
+    field TEXT NOT NULL CHECK (field IN ('message', 'name', 'title', 'any')),
+    pattern TEXT NOT NULL,
+    action TEXT NOT NULL CHECK (action IN ('replace', 'reject', 'ban', 'hold')),
+    -- only used by 'replace', may reference capture groups like $1
+    replacement TEXT NOT NULL DEFAULT '',
+    -- shown to the poster by 'reject' and 'ban'
+    reason TEXT NOT NULL DEFAULT '',
+    -- ban length for 'ban', NULL is permanent
+    ban_minutes INTEGER
+);
+CREATE INDEX board_rules_board_idx ON board_rules (board);
+
+-- held posts are only shown once a moderator lets them through
+ALTER TABLE posts ADD COLUMN pending BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,7 @@
+-- posts from identities younger than premod_age minutes, or with fewer than
+-- premod_posts approved posts, wait for a moderator; NULL turns a check off
+ALTER TABLE boards
+    ADD COLUMN premod_age INTEGER,
+    ADD COLUMN premod_posts INTEGER;
+
+CREATE INDEX posts_pending_idx ON posts (thread) WHERE pending;
@@ -0,0 +1,3 @@
+-- shadow-banned identities can keep posting, but only they (and staff) see their posts
+ALTER TABLE bans ADD COLUMN shadow BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE posts ADD COLUMN shadow BOOLEAN NOT NULL DEFAULT false;
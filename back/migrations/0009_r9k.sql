@@ -0,0 +1,23 @@
+-- boards in r9k mode reject anything that was ever posted on them before,
+-- repeat offenders get muted for r9k_mute_seconds, doubled on every violation
+ALTER TABLE boards
+    ADD COLUMN r9k BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN r9k_mute_seconds INTEGER;
+
+ALTER TABLE images ADD COLUMN hash BYTEA;
+
+-- hashes of normalized messages and of image files, kept for every board
+-- so turning r9k mode on covers everything posted before
+CREATE TABLE r9k_hashes (
+    board TEXT NOT NULL REFERENCES boards (code) ON DELETE CASCADE,
+    hash BYTEA NOT NULL,
+    PRIMARY KEY (board, hash)
+);
+
+CREATE TABLE r9k_mutes (
+    board TEXT NOT NULL REFERENCES boards (code) ON DELETE CASCADE,
+    identity TEXT NOT NULL,
+    violations INTEGER NOT NULL DEFAULT 0,
+    muted_until TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (board, identity)
+);
@@ -0,0 +1,21 @@
+ALTER TABLE staff ADD COLUMN admin BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE boards
+    -- threads past this many get archived
+    ADD COLUMN max_threads INTEGER NOT NULL DEFAULT 100,
+    -- replies past this many don't bump the thread
+    ADD COLUMN bump_limit INTEGER NOT NULL DEFAULT 300,
+    -- images per thread, counting the opening post's
+    ADD COLUMN image_limit INTEGER NOT NULL DEFAULT 150,
+    ADD COLUMN op_image_required BOOLEAN NOT NULL DEFAULT true,
+    -- comma separated mime types
+    ADD COLUMN allowed_media TEXT NOT NULL DEFAULT 'image/png,image/jpeg,image/gif,image/webp',
+    -- in bytes
+    ADD COLUMN max_file_size INTEGER NOT NULL DEFAULT 4194304,
+    -- in seconds, NULL falls back to the server-wide defaults
+    ADD COLUMN thread_cooldown INTEGER,
+    ADD COLUMN reply_cooldown INTEGER,
+    ADD COLUMN image_reply_cooldown INTEGER,
+    ADD COLUMN default_name TEXT NOT NULL DEFAULT 'Anonymous',
+    ADD COLUMN nsfw BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN text_only BOOLEAN NOT NULL DEFAULT false;
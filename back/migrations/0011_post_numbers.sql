@@ -0,0 +1,24 @@
+-- the last number handed out on the board, bumped in the same statement that inserts the post
+ALTER TABLE boards ADD COLUMN post_counter BIGINT NOT NULL DEFAULT 0;
+
+ALTER TABLE posts
+    ADD COLUMN board TEXT REFERENCES boards (code),
+    ADD COLUMN number BIGINT;
+
+UPDATE posts p
+SET board = numbered.board, number = numbered.number
+FROM (
+    SELECT p.id, t.board, row_number() OVER (PARTITION BY t.board ORDER BY p.id) AS number
+    FROM posts p
+    JOIN threads t ON p.thread = t.id
+) numbered
+WHERE p.id = numbered.id;
+
+UPDATE boards b
+SET post_counter = COALESCE((SELECT max(number) FROM posts WHERE board = b.code), 0);
+
+ALTER TABLE posts
+    ALTER COLUMN board SET NOT NULL,
+    ALTER COLUMN number SET NOT NULL;
+
+CREATE UNIQUE INDEX posts_board_number ON posts (board, number);
@@ -0,0 +1,4 @@
+-- NULL for files that couldn't be decoded as images, and for everything uploaded before this
+ALTER TABLE images
+    ADD COLUMN width INTEGER,
+    ADD COLUMN height INTEGER;
@@ -0,0 +1,23 @@
+-- titles are only searchable through the thread's opening post, and weigh more than messages
+ALTER TABLE posts ADD COLUMN search TSVECTOR;
+
+CREATE FUNCTION posts_search_update() RETURNS trigger AS $$
+BEGIN
+    NEW.search :=
+        setweight(to_tsvector('english', COALESCE((
+            SELECT title FROM threads
+            WHERE id = NEW.thread
+            AND NOT EXISTS (SELECT 1 FROM posts WHERE thread = NEW.thread AND id < NEW.id)
+        ), '')), 'A') ||
+        setweight(to_tsvector('english', NEW.message), 'B');
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER posts_search_update
+    BEFORE INSERT OR UPDATE OF message ON posts
+    FOR EACH ROW EXECUTE PROCEDURE posts_search_update();
+
+UPDATE posts SET message = message;
+
+CREATE INDEX posts_search ON posts USING GIN (search);
@@ -0,0 +1,12 @@
+-- live events for every instance, NOTIFY only carries the id so listeners that missed
+-- notifications while reconnecting can catch up from here
+CREATE TABLE events (
+    id BIGSERIAL PRIMARY KEY,
+    board TEXT NOT NULL,
+    thread INTEGER NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('post', 'delete', 'lock')),
+    post BIGINT,
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX events_created ON events (created);
@@ -0,0 +1,3 @@
+ALTER TABLE events
+    DROP CONSTRAINT events_kind_check,
+    ADD CONSTRAINT events_kind_check CHECK (kind IN ('post', 'delete', 'lock', 'archive'));
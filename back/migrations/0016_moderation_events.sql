@@ -0,0 +1,8 @@
+-- shown under a post whose author got banned for it
+ALTER TABLE posts ADD COLUMN ban_message TEXT;
+
+ALTER TABLE events
+    DROP CONSTRAINT events_kind_check,
+    ADD CONSTRAINT events_kind_check CHECK (kind IN (
+        'post', 'delete', 'lock', 'sticky', 'archive', 'edit', 'remove_image', 'ban_message', 'move'
+    ));
@@ -0,0 +1,13 @@
+-- posts quoting someone else's posts, for reply notifications
+CREATE TABLE replies (
+    id BIGSERIAL PRIMARY KEY,
+    -- whoever wrote the quoted post
+    identity TEXT NOT NULL,
+    post BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    quoted BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    read BOOLEAN NOT NULL DEFAULT false,
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (post, quoted)
+);
+
+CREATE INDEX replies_unread ON replies (identity, id) WHERE read = false;
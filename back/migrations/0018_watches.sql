@@ -0,0 +1,12 @@
+-- threads followed by an identity, there's no foreign key so watchers can still be found
+-- and told when a thread goes away, stale watches are deleted periodically
+CREATE TABLE watches (
+    identity TEXT NOT NULL,
+    thread INTEGER NOT NULL,
+    -- id of the newest post when the thread was last looked at
+    last_seen BIGINT NOT NULL DEFAULT 0,
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (identity, thread)
+);
+
+CREATE INDEX watches_thread ON watches (thread);
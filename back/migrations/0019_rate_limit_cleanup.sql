@@ -0,0 +1,2 @@
+-- the cooldown a bucket refills at, in seconds, so full ones can be told apart and deleted
+ALTER TABLE rate_limits ADD COLUMN cooldown DOUBLE PRECISION NOT NULL DEFAULT 0;
@@ -0,0 +1,15 @@
+-- message and file hashes were kept side by side, they get a kind of their own
+-- so a message can never be a duplicate of a file
+ALTER TABLE r9k_hashes ADD COLUMN kind TEXT NOT NULL DEFAULT 'message';
+
+UPDATE r9k_hashes h SET kind = 'file'
+WHERE EXISTS (
+    SELECT 1 FROM posts p JOIN images i ON p.image = i.id
+    WHERE p.board = h.board AND i.hash = h.hash
+);
+
+ALTER TABLE r9k_hashes
+    ALTER COLUMN kind DROP DEFAULT,
+    ADD CONSTRAINT r9k_hashes_kind CHECK (kind IN ('message', 'file')),
+    DROP CONSTRAINT r9k_hashes_pkey,
+    ADD PRIMARY KEY (board, kind, hash);
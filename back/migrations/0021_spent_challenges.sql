@@ -0,0 +1,7 @@
+-- proofs of work that went into a post, kept until they'd have expired anyway
+-- so a replay is turned away by every instance, not just the one that saw it first
+CREATE TABLE spent_challenges (
+    challenge TEXT PRIMARY KEY,
+    expires TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX spent_challenges_expires_idx ON spent_challenges (expires);
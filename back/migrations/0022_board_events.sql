@@ -0,0 +1,2 @@
+-- listeners read events back one board at a time
+CREATE INDEX events_board_id ON events (board, id);
@@ -15,6 +15,41 @@ pub struct Config {
     pub https: bool,
     #[envconfig(from = "STATIC_DIR", default = "./tmp")]
     pub static_dir: String,
+    // cooldowns are in seconds, boards without cooldowns of their own use these
+    #[envconfig(from = "THREAD_COOLDOWN", default = "60")]
+    pub thread_cooldown: u64,
+    #[envconfig(from = "REPLY_COOLDOWN", default = "10")]
+    pub reply_cooldown: u64,
+    #[envconfig(from = "IMAGE_REPLY_COOLDOWN", default = "20")]
+    pub image_reply_cooldown: u64,
+    // how many actions can be made back to back before the cooldown kicks in
+    #[envconfig(from = "RATE_LIMIT_BURST", default = "2")]
+    pub rate_limit_burst: u32,
+    // "memory" or "postgres", the latter is needed to share limits between instances
+    #[envconfig(from = "RATE_LIMIT_STORE", default = "memory")]
+    pub rate_limit_store: String,
+    // seconds a captcha stays valid after being served
+    #[envconfig(from = "CAPTCHA_TTL", default = "300")]
+    pub captcha_ttl: u32,
+    // seconds a proof of work challenge stays valid after being issued
+    #[envconfig(from = "POW_TTL", default = "600")]
+    pub pow_ttl: u64,
+    // posts per minute on a board before proof of work difficulty starts going up
+    #[envconfig(from = "POW_SPIKE_THRESHOLD", default = "30")]
+    pub pow_spike_threshold: u32,
+    // live update streams open on this instance, every websocket subscription counts as one
+    #[envconfig(from = "MAX_SUBSCRIBERS", default = "10000")]
+    pub max_subscribers: usize,
+    #[envconfig(from = "MAX_SUBSCRIBERS_PER_IP", default = "20")]
+    pub max_subscribers_per_ip: usize,
+    // comma separated, pages elsewhere that may open websockets, e.g. "http://localhost:3000"
+    // pages served from the same host always can
+    #[envconfig(from = "ALLOWED_ORIGINS", default = "")]
+    pub allowed_origins: String,
+    // take client addresses from Forwarded/X-Forwarded-For, only safe behind a proxy that
+    // sets them, otherwise anyone can pick the address their rate limits are counted against
+    #[envconfig(from = "TRUST_PROXY", default = "false")]
+    pub trust_proxy: bool,
 }
 
 impl Config {
@@ -25,6 +60,8 @@ impl Config {
         );
         println!("{}: http://{}", "Server address".cyan(), self.address);
         println!("{}: {}", "Postgres address".cyan(), self.db_url);
+        println!("{}: {}", "Rate limit store".cyan(), self.rate_limit_store);
+        println!("{}: {}", "Trust proxy headers".cyan(), self.trust_proxy);
         println!(
             "{}",
             "===================================================".cyan()
@@ -1,37 +1,492 @@
+use crate::util::r9k;
 use futures::join;
 use serde::{Deserialize, Serialize};
-use sqlx::{PgPool, Result};
+use sqlx::{pool::PoolConnection, PgConnection, PgPool, Result, Transaction};
+use std::collections::HashMap;
 use time::OffsetDateTime;
 
-#[derive(Serialize)]
+type Tx = Transaction<PoolConnection<PgConnection>>;
+
+// settings missing from a new board get the same defaults as the table columns
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Board {
-    code: String,
-    name: String,
-    description: String,
+    pub code: String,
+    pub name: String,
+    pub description: String,
+    pub locked: bool,
+    pub captcha: String,
+    pub captcha_identity_age: i32,
+    pub pow_difficulty: i32,
+    pub premod_age: Option<i32>,
+    pub premod_posts: Option<i32>,
+    pub r9k: bool,
+    pub r9k_mute_seconds: Option<i32>,
+    pub max_threads: i32,
+    pub bump_limit: i32,
+    pub image_limit: i32,
+    pub op_image_required: bool,
+    pub allowed_media: String,
+    pub max_file_size: i32,
+    pub thread_cooldown: Option<i32>,
+    pub reply_cooldown: Option<i32>,
+    pub image_reply_cooldown: Option<i32>,
+    pub default_name: String,
+    pub nsfw: bool,
+    pub text_only: bool,
+    // last post number handed out, only ever changed by Post::post
+    #[serde(skip_deserializing)]
+    pub post_counter: i64,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Board {
+            code: String::new(),
+            name: String::new(),
+            description: String::new(),
+            locked: false,
+            captcha: "never".to_owned(),
+            captcha_identity_age: 60,
+            pow_difficulty: 0,
+            premod_age: None,
+            premod_posts: None,
+            r9k: false,
+            r9k_mute_seconds: None,
+            max_threads: 100,
+            bump_limit: 300,
+            image_limit: 150,
+            op_image_required: true,
+            allowed_media: "image/png,image/jpeg,image/gif,image/webp".to_owned(),
+            max_file_size: 4 * 1024 * 1024,
+            thread_cooldown: None,
+            reply_cooldown: None,
+            image_reply_cooldown: None,
+            default_name: "Anonymous".to_owned(),
+            nsfw: false,
+            text_only: false,
+            post_counter: 0,
+        }
+    }
 }
+
+pub enum CaptchaMode {
+    Never,
+    Always,
+    Threads,
+    // identities younger than this many minutes
+    NewIdentities(i64),
+}
+
 impl Board {
+    pub fn captcha_mode(&self) -> CaptchaMode {
+        match self.captcha.as_str() {
+            "always" => CaptchaMode::Always,
+            "threads" => CaptchaMode::Threads,
+            "new_identities" => CaptchaMode::NewIdentities(self.captcha_identity_age as i64),
+            _ => CaptchaMode::Never,
+        }
+    }
+
+    pub fn allows_media(&self, content_type: &str) -> bool {
+        self.allowed_media
+            .split(',')
+            .any(|allowed| allowed.trim().eq_ignore_ascii_case(content_type))
+    }
+
+    pub async fn fetch(pool: &PgPool, code: &str) -> Result<Option<Self>> {
+        sqlx::query_as!(Board, "SELECT * FROM boards WHERE code = $1", code)
+            .fetch_optional(pool)
+            .await
+    }
     pub async fn fetch_all(pool: &PgPool) -> Result<Vec<Self>> {
         sqlx::query_as!(Board, "SELECT * FROM boards")
             .fetch_all(pool)
             .await
     }
+    pub async fn post(pool: &PgPool, board: &Board) -> Result<Self> {
+        sqlx::query_as!(
+            Board,
+            "INSERT INTO boards ( \
+                code, name, description, locked, captcha, captcha_identity_age, \
+                pow_difficulty, premod_age, premod_posts, r9k, r9k_mute_seconds, max_threads, \
+                bump_limit, image_limit, op_image_required, allowed_media, max_file_size, \
+                thread_cooldown, reply_cooldown, image_reply_cooldown, default_name, nsfw, \
+                text_only \
+            ) \
+            VALUES ( \
+                $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, \
+                $18, $19, $20, $21, $22, $23 \
+            ) \
+            RETURNING *",
+            board.code,
+            board.name,
+            board.description,
+            board.locked,
+            board.captcha,
+            board.captcha_identity_age,
+            board.pow_difficulty,
+            board.premod_age,
+            board.premod_posts,
+            board.r9k,
+            board.r9k_mute_seconds,
+            board.max_threads,
+            board.bump_limit,
+            board.image_limit,
+            board.op_image_required,
+            board.allowed_media,
+            board.max_file_size,
+            board.thread_cooldown,
+            board.reply_cooldown,
+            board.image_reply_cooldown,
+            board.default_name,
+            board.nsfw,
+            board.text_only
+        )
+        .fetch_one(pool)
+        .await
+    }
+    // the code is what everything else points at, so it can't be changed
+    pub async fn update(pool: &PgPool, board: &Board) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            Board,
+            "UPDATE boards SET \
+                name = $2, description = $3, locked = $4, captcha = $5, \
+                captcha_identity_age = $6, pow_difficulty = $7, premod_age = $8, \
+                premod_posts = $9, r9k = $10, r9k_mute_seconds = $11, max_threads = $12, \
+                bump_limit = $13, image_limit = $14, op_image_required = $15, \
+                allowed_media = $16, max_file_size = $17, thread_cooldown = $18, \
+                reply_cooldown = $19, image_reply_cooldown = $20, default_name = $21, \
+                nsfw = $22, text_only = $23 \
+            WHERE code = $1 \
+            RETURNING *",
+            board.code,
+            board.name,
+            board.description,
+            board.locked,
+            board.captcha,
+            board.captcha_identity_age,
+            board.pow_difficulty,
+            board.premod_age,
+            board.premod_posts,
+            board.r9k,
+            board.r9k_mute_seconds,
+            board.max_threads,
+            board.bump_limit,
+            board.image_limit,
+            board.op_image_required,
+            board.allowed_media,
+            board.max_file_size,
+            board.thread_cooldown,
+            board.reply_cooldown,
+            board.image_reply_cooldown,
+            board.default_name,
+            board.nsfw,
+            board.text_only
+        )
+        .fetch_optional(pool)
+        .await
+    }
+    // takes every thread, post and image on the board with it, None if there's no such board
+    // otherwise the files for the caller to delete
+    // a delete event for every thread's opening post goes out once it's all gone
+    pub async fn delete(pool: &PgPool, code: &str) -> Result<Option<Vec<String>>> {
+        let mut tx = pool.begin().await?;
+        StoredEvent::lock(&mut tx, code).await?;
+        sqlx::query!(
+            "WITH e AS ( \
+                INSERT INTO events (board, thread, kind, post) \
+                SELECT t.board, t.id, 'delete', (SELECT min(id) FROM posts WHERE thread = t.id) \
+                FROM threads t WHERE t.board = $1 \
+                RETURNING id, board \
+            ) \
+            SELECT pg_notify('board_' || board, id::text) FROM e",
+            code
+        )
+        .execute(&mut tx)
+        .await?;
+        let images = sqlx::query_as!(
+            ImageFiles,
+            "WITH deleted AS ( \
+                DELETE FROM posts p USING threads t \
+                WHERE p.thread = t.id AND t.board = $1 \
+                RETURNING p.image \
+            ) \
+            DELETE FROM images WHERE id IN (SELECT image FROM deleted) \
+            RETURNING path, preview_path",
+            code
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        sqlx::query!("DELETE FROM threads WHERE board = $1", code)
+            .execute(&mut tx)
+            .await?;
+        let deleted = sqlx::query!("DELETE FROM boards WHERE code = $1", code)
+            .execute(&mut tx)
+            .await?;
+        if deleted == 0 {
+            tx.rollback().await?;
+            return Ok(None);
+        }
+        tx.commit().await?;
+        Ok(Some(image_files(images)))
+    }
+    // archives everything past the board's thread limit, stickies don't count towards it
+    // and publishes an archive event for every thread that got closed, see StoredEvent::publish
     pub async fn update_locks(pool: &PgPool, board: &str) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        StoredEvent::lock(&mut tx, board).await?;
         sqlx::query!(
-            "UPDATE threads \
-            SET open = false \
-            WHERE id IN ( \
-                SELECT id FROM threads \
-                WHERE board = $1 \
-                ORDER BY last_updated DESC \
-                OFFSET 100 \
-                LIMIT 1 \
-            )",
+            "WITH archived AS ( \
+                UPDATE threads \
+                SET open = false \
+                WHERE open = true AND id IN ( \
+                    SELECT id FROM threads \
+                    WHERE board = $1 AND sticky = false \
+                    ORDER BY last_updated DESC \
+                    OFFSET (SELECT max_threads FROM boards WHERE code = $1) \
+                ) \
+                RETURNING id, board \
+            ), e AS ( \
+                INSERT INTO events (board, thread, kind) \
+                SELECT board, id, 'archive' FROM archived \
+                RETURNING id, board \
+            ) \
+            SELECT pg_notify('board_' || board, id::text) FROM e",
             board
         )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+// who's looking, held and shadow-banned posts are only shown to their author and to staff
+#[derive(Clone, Debug)]
+pub struct Viewer {
+    pub identity: Option<String>,
+    pub staff: bool,
+}
+
+pub struct Staff;
+impl Staff {
+    pub async fn is_staff(pool: &PgPool, identity: &str) -> Result<bool> {
+        let staff = sqlx::query!("SELECT identity FROM staff WHERE identity = $1", identity)
+            .fetch_optional(pool)
+            .await?;
+        Ok(staff.is_some())
+    }
+    // admins manage the boards themselves
+    pub async fn is_admin(pool: &PgPool, identity: &str) -> Result<bool> {
+        let admin = sqlx::query!(
+            "SELECT identity FROM staff WHERE identity = $1 AND admin = true",
+            identity
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(admin.is_some())
+    }
+}
+
+struct StoredCaptcha {
+    answer: Vec<u8>,
+    valid: bool,
+}
+
+pub struct Captcha;
+impl Captcha {
+    pub async fn store(pool: &PgPool, identity: &str, answer: &[u8], ttl: u32) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO captchas (identity, answer, expires) \
+            VALUES ($1, $2, now() + make_interval(secs => $3)) \
+            ON CONFLICT (identity) DO UPDATE \
+            SET answer = EXCLUDED.answer, expires = EXCLUDED.expires",
+            identity,
+            answer,
+            ttl as f64
+        )
         .execute(pool)
         .await?;
         Ok(())
     }
+    // every captcha is good for a single attempt, right or wrong
+    pub async fn take(pool: &PgPool, identity: &str) -> Result<Option<Vec<u8>>> {
+        let captcha = sqlx::query_as!(
+            StoredCaptcha,
+            "DELETE FROM captchas WHERE identity = $1 \
+            RETURNING answer, expires > now() AS valid",
+            identity
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(captcha.filter(|c| c.valid).map(|c| c.answer))
+    }
+    // unanswered captchas, run now and then rather than on every request
+    pub async fn delete_expired(pool: &PgPool) -> Result<()> {
+        sqlx::query!("DELETE FROM captchas WHERE expires < now()")
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct SpentChallenge;
+impl SpentChallenge {
+    pub async fn exists(pool: &PgPool, challenge: &str) -> Result<bool> {
+        let spent = sqlx::query_as!(
+            Exists,
+            "SELECT EXISTS (SELECT 1 FROM spent_challenges WHERE challenge = $1) AS exists",
+            challenge
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(spent.exists)
+    }
+    // false if someone spent it first
+    pub async fn spend(pool: &PgPool, challenge: &str, expires: u64) -> Result<bool> {
+        let spent = sqlx::query_as!(
+            Exists,
+            "INSERT INTO spent_challenges (challenge, expires) VALUES ($1, to_timestamp($2)) \
+            ON CONFLICT (challenge) DO NOTHING \
+            RETURNING true AS exists",
+            challenge,
+            expires as f64
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(spent.is_some())
+    }
+    // expired challenges are turned away without looking them up
+    pub async fn delete_expired(pool: &PgPool) -> Result<()> {
+        sqlx::query!("DELETE FROM spent_challenges WHERE expires < now()")
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct Ban {
+    id: i32,
+    identity: String,
+    board: Option<String>,
+    pub reason: String,
+    created: OffsetDateTime,
+    pub expires: Option<OffsetDateTime>,
+    pub shadow: bool,
+}
+
+pub struct BanNew {
+    pub identity: String,
+    pub board: Option<String>,
+    pub reason: String,
+    pub minutes: Option<i32>,
+    pub shadow: bool,
+}
+
+impl Ban {
+    // the ban that runs out last if there are several, visible bans win over shadow ones
+    pub async fn fetch_active(pool: &PgPool, identity: &str, board: &str) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            Ban,
+            "SELECT id, identity, board, reason, created, expires, shadow FROM bans \
+            WHERE identity = $1 \
+            AND (board IS NULL OR board = $2) \
+            AND (expires IS NULL OR expires > now()) \
+            ORDER BY shadow ASC, expires DESC NULLS FIRST \
+            LIMIT 1",
+            identity,
+            board
+        )
+        .fetch_optional(pool)
+        .await
+    }
+    pub async fn post(pool: &PgPool, ban: &BanNew) -> Result<Self> {
+        sqlx::query_as!(
+            Ban,
+            "INSERT INTO bans (identity, board, reason, expires, shadow) \
+            VALUES ($1, $2, $3, now() + make_interval(mins => $4), $5) \
+            RETURNING id, identity, board, reason, created, expires, shadow",
+            ban.identity,
+            ban.board,
+            ban.reason,
+            ban.minutes,
+            ban.shadow
+        )
+        .fetch_one(pool)
+        .await
+    }
+    pub async fn delete(pool: &PgPool, id: i32) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            Ban,
+            "DELETE FROM bans WHERE id = $1 \
+            RETURNING id, identity, board, reason, created, expires, shadow",
+            id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+}
+
+#[derive(Serialize)]
+pub struct BoardRule {
+    pub id: i32,
+    pub board: String,
+    pub field: String,
+    pub pattern: String,
+    pub action: String,
+    pub replacement: String,
+    pub reason: String,
+    pub ban_minutes: Option<i32>,
+}
+
+pub struct BoardRuleNew {
+    pub field: String,
+    pub pattern: String,
+    pub action: String,
+    pub replacement: String,
+    pub reason: String,
+    pub ban_minutes: Option<i32>,
+}
+
+impl BoardRule {
+    pub async fn fetch_for_board(pool: &PgPool, board: &str) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            BoardRule,
+            "SELECT * FROM board_rules WHERE board = $1 ORDER BY id ASC",
+            board
+        )
+        .fetch_all(pool)
+        .await
+    }
+    pub async fn post(pool: &PgPool, board: &str, rule: &BoardRuleNew) -> Result<Self> {
+        sqlx::query_as!(
+            BoardRule,
+            "INSERT INTO board_rules (board, field, pattern, action, replacement, reason, ban_minutes) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7) \
+            RETURNING id, board, field, pattern, action, replacement, reason, ban_minutes",
+            board,
+            rule.field,
+            rule.pattern,
+            rule.action,
+            rule.replacement,
+            rule.reason,
+            rule.ban_minutes
+        )
+        .fetch_one(pool)
+        .await
+    }
+    pub async fn delete(pool: &PgPool, id: i32) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            BoardRule,
+            "DELETE FROM board_rules WHERE id = $1 \
+            RETURNING id, board, field, pattern, action, replacement, reason, ban_minutes",
+            id
+        )
+        .fetch_optional(pool)
+        .await
+    }
 }
 
 pub struct ThreadNew {
@@ -40,15 +495,34 @@ pub struct ThreadNew {
     pub name: String,
     pub message: String,
     pub image: Option<ImageNew>,
+    pub pending: bool,
+    pub shadow: bool,
+    pub r9k: bool,
+}
+
+// what posting hands back, nothing is saved when it's a duplicate
+pub enum Posted<T> {
+    Done(T),
+    // only on boards in r9k mode
+    Duplicate(R9kKind),
+}
+
+pub struct ReplyCounts {
+    // id of the opening post
+    pub opening: i64,
+    pub replies: i64,
+    pub images: i64,
 }
 
 #[derive(Serialize)]
 pub struct Thread {
-    id: i32,
-    last_updated: OffsetDateTime,
-    open: bool,
-    board: String,
-    title: String,
+    pub id: i32,
+    pub last_updated: OffsetDateTime,
+    pub open: bool,
+    pub board: String,
+    pub title: String,
+    pub sticky: bool,
+    pub locked: bool,
 }
 impl Thread {
     pub async fn fetch(pool: &PgPool, thread_id: i32) -> Result<Option<Self>> {
@@ -56,49 +530,155 @@ impl Thread {
             .fetch_optional(pool)
             .await
     }
-    pub async fn fetch_catalog(pool: &PgPool, board: &str) -> Result<Vec<Self>> {
-        sqlx::query_as!(
-            Thread,
-            "SELECT * FROM threads \
-            WHERE board = $1 AND open = true \
-            ORDER BY last_updated DESC \
-            LIMIT 100",
-            board
-        )
-        .fetch_all(pool)
-        .await
-    }
+    // the thread and its opening post go in together or not at all
     pub async fn post(
         pool: &PgPool,
         new_thread: ThreadNew,
         identity: String,
-    ) -> Result<ThreadWithPosts> {
+    ) -> Result<Posted<ThreadWithPosts>> {
+        let mut tx = pool.begin().await?;
         let thread: Thread = sqlx::query_as!(
             Thread,
             "INSERT INTO threads (board, title) \
             VALUES ($1, $2) \
-            RETURNING id, last_updated, open, board, title",
+            RETURNING id, last_updated, open, board, title, sticky, locked",
             new_thread.board,
             new_thread.title
         )
-        .fetch_one(pool)
+        .fetch_one(&mut tx)
         .await?;
 
-        let post = Post::post(
-            pool,
+        let posted = Post::insert(
+            &mut tx,
             &PostNew {
                 thread: thread.id,
                 name: new_thread.name,
                 message: new_thread.message,
                 identity: identity,
                 image: new_thread.image,
+                pending: new_thread.pending,
+                shadow: new_thread.shadow,
+                r9k: new_thread.r9k,
             },
         )
         .await?;
+        let post = match posted {
+            Posted::Done(post) => post,
+            Posted::Duplicate(kind) => {
+                tx.rollback().await?;
+                return Ok(Posted::Duplicate(kind));
+            }
+        };
+        tx.commit().await?;
+
+        Ok(Posted::Done((thread, vec![post]).into()))
+    }
+    // public posts only, None if the thread has no posts at all
+    pub async fn reply_counts(pool: &PgPool, thread_id: i32) -> Result<Option<ReplyCounts>> {
+        sqlx::query_as!(
+            ReplyCounts,
+            "SELECT op.id AS opening, \
+                (SELECT count(*) FROM posts \
+                    WHERE thread = $1 AND id <> op.id AND NOT pending AND NOT shadow) AS replies, \
+                (SELECT count(image) FROM posts \
+                    WHERE thread = $1 AND id <> op.id AND NOT pending AND NOT shadow) AS images \
+            FROM (SELECT id FROM posts WHERE thread = $1 ORDER BY id LIMIT 1) op",
+            thread_id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+    pub async fn image_count(pool: &PgPool, thread_id: i32) -> Result<i64> {
+        let count = sqlx::query_as!(
+            PostCount,
+            "SELECT count(image) AS count FROM posts WHERE thread = $1",
+            thread_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(count.count)
+    }
+    // past the bump limit replies still go in, they just don't move the thread up
+    async fn bump(tx: &mut Tx, thread_id: i32) -> Result<()> {
+        sqlx::query!(
+            "UPDATE threads t SET last_updated = now() \
+            FROM boards b \
+            WHERE t.id = $1 AND b.code = t.board \
+            AND (SELECT count(*) - 1 FROM posts WHERE thread = $1) <= b.bump_limit",
+            thread_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+    pub async fn set_sticky(pool: &PgPool, thread_id: i32, sticky: bool) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            Thread,
+            "UPDATE threads SET sticky = $2 WHERE id = $1 \
+            RETURNING id, last_updated, open, board, title, sticky, locked",
+            thread_id,
+            sticky
+        )
+        .fetch_optional(pool)
+        .await
+    }
+    // held and shadowed posts included, they'd come along on a move
+    pub async fn has_images(pool: &PgPool, thread_id: i32) -> Result<bool> {
+        let images = sqlx::query_as!(
+            Exists,
+            "SELECT EXISTS ( \
+                SELECT 1 FROM posts WHERE thread = $1 AND image IS NOT NULL \
+            ) AS exists",
+            thread_id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(images.exists)
+    }
+    // the posts get new numbers on the new board, >>links to their old ones stop resolving
+    pub async fn move_to(pool: &PgPool, thread_id: i32, board: &str) -> Result<Option<Self>> {
+        let mut tx = pool.begin().await?;
+        let thread = sqlx::query_as!(
+            Thread,
+            "UPDATE threads SET board = $2 WHERE id = $1 \
+            RETURNING id, last_updated, open, board, title, sticky, locked",
+            thread_id,
+            board
+        )
+        .fetch_optional(&mut tx)
+        .await?;
 
-        Board::update_locks(pool, &thread.board).await?;
+        if thread.is_some() {
+            sqlx::query!(
+                "WITH moved AS ( \
+                    SELECT id, row_number() OVER (ORDER BY id) AS n FROM posts WHERE thread = $1 \
+                ), counter AS ( \
+                    UPDATE boards SET post_counter = post_counter + (SELECT count(*) FROM moved) \
+                    WHERE code = $2 \
+                    RETURNING post_counter - (SELECT count(*) FROM moved) AS start \
+                ) \
+                UPDATE posts SET board = $2, number = counter.start + moved.n \
+                FROM moved, counter WHERE posts.id = moved.id",
+                thread_id,
+                board
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
 
-        Ok((thread, vec![post]).into())
+        Ok(thread)
+    }
+    pub async fn set_locked(pool: &PgPool, thread_id: i32, locked: bool) -> Result<Option<Self>> {
+        sqlx::query_as!(
+            Thread,
+            "UPDATE threads SET locked = $2 WHERE id = $1 \
+            RETURNING id, last_updated, open, board, title, sticky, locked",
+            thread_id,
+            locked
+        )
+        .fetch_optional(pool)
+        .await
     }
 }
 
@@ -108,6 +688,8 @@ pub struct ThreadWithPosts {
     board: String,
     pub open: bool,
     title: String,
+    sticky: bool,
+    locked: bool,
     posts: Vec<Post>,
 }
 
@@ -118,15 +700,23 @@ impl From<(Thread, Vec<Post>)> for ThreadWithPosts {
             board: thread.board,
             open: thread.open,
             title: thread.title,
+            sticky: thread.sticky,
+            locked: thread.locked,
             posts,
         }
     }
 }
 
 impl ThreadWithPosts {
-    pub async fn fetch(pool: &PgPool, thread_id: i32) -> Result<Option<Self>> {
+    pub fn last_post_id(&self) -> Option<i64> {
+        self.posts.last().map(|post| post.id)
+    }
+    pub fn post_ids(&self) -> impl Iterator<Item = i64> + '_ {
+        self.posts.iter().map(|post| post.id)
+    }
+    pub async fn fetch(pool: &PgPool, thread_id: i32, viewer: &Viewer) -> Result<Option<Self>> {
         if let Some(thread) = Thread::fetch(pool, thread_id).await? {
-            let posts = Post::fetch_for_thread(pool, thread_id).await?;
+            let posts = Post::fetch_for_thread(pool, thread_id, viewer).await?;
             Ok(Some((thread, posts).into()))
         } else {
             Ok(None)
@@ -134,138 +724,1820 @@ impl ThreadWithPosts {
     }
 }
 
-#[derive(Serialize)]
-pub struct Post {
-    id: i64,
-    pub thread: i32,
-    name: String,
-    timestamp: i64,
-    message: String,
-    image: Option<Image>,
+// how much of the opening post's message a catalog entry carries
+const CATALOG_EXCERPT_LENGTH: i32 = 200;
+// a post quoting more than this only notifies the first ones
+const MAX_QUOTES: usize = 10;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CatalogSort {
+    Bump,
+    Created,
+    Replies,
+    LastReply,
 }
 
-struct PostInner {
-    id: i64,
-    thread: i32,
-    name: String,
-    date: OffsetDateTime,
-    message: String,
-    identity: String,
+impl Default for CatalogSort {
+    fn default() -> Self {
+        CatalogSort::Bump
+    }
+}
+
+impl CatalogSort {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Bump => "bump",
+            Self::Created => "created",
+            Self::Replies => "replies",
+            Self::LastReply => "last_reply",
+        }
+    }
+}
+
+struct CatalogRow {
+    id: i32,
+    last_updated: OffsetDateTime,
+    open: bool,
+    board: String,
+    title: String,
+    sticky: bool,
+    locked: bool,
+    created: OffsetDateTime,
+    excerpt: String,
     image_id: Option<i64>,
     image_name: Option<String>,
     image_path: Option<String>,
     image_preview_path: Option<String>,
+    image_width: Option<i32>,
+    image_height: Option<i32>,
+    reply_count: i64,
+    image_count: i64,
+    posters: i64,
+    last_reply: Option<OffsetDateTime>,
+    bump_locked: bool,
 }
-pub struct PostNew {
-    pub thread: i32,
-    pub name: String,
-    pub message: String,
-    pub identity: String,
-    pub image: Option<ImageNew>,
+
+// replies and images don't count the opening post, posters does
+#[derive(Serialize)]
+pub struct CatalogThread {
+    #[serde(flatten)]
+    thread: Thread,
+    created: i64,
+    excerpt: String,
+    thumbnail: Option<Image>,
+    reply_count: i64,
+    image_count: i64,
+    posters: i64,
+    last_reply: Option<i64>,
+    bump_locked: bool,
 }
-impl From<PostInner> for Post {
-    fn from(pi: PostInner) -> Self {
-        Post {
-            id: pi.id,
-            thread: pi.thread,
-            name: pi.name,
-            timestamp: pi.date.timestamp(),
-            message: pi.message,
-            image: if let Some(image_id) = pi.image_id {
+
+impl From<CatalogRow> for CatalogThread {
+    fn from(row: CatalogRow) -> Self {
+        CatalogThread {
+            thread: Thread {
+                id: row.id,
+                last_updated: row.last_updated,
+                open: row.open,
+                board: row.board,
+                title: row.title,
+                sticky: row.sticky,
+                locked: row.locked,
+            },
+            created: row.created.timestamp(),
+            excerpt: row.excerpt,
+            thumbnail: if let Some(image_id) = row.image_id {
                 Some(Image {
                     id: image_id,
-                    name: pi.image_name.unwrap(),
-                    path: pi.image_path.unwrap(),
-                    preview_path: pi.image_preview_path.unwrap(),
+                    name: row.image_name.unwrap(),
+                    path: row.image_path.unwrap(),
+                    preview_path: row.image_preview_path.unwrap(),
+                    width: row.image_width,
+                    height: row.image_height,
                 })
             } else {
                 None
             },
+            reply_count: row.reply_count,
+            image_count: row.image_count,
+            posters: row.posters,
+            last_reply: row.last_reply.map(|date| date.timestamp()),
+            bump_locked: row.bump_locked,
         }
     }
 }
 
-impl Post {
-    pub async fn fetch_for_thread(pool: &PgPool, thread_id: i32) -> Result<Vec<Self>> {
-        let res = sqlx::query_as!(PostInner, "\
-          SELECT p.id, p.message, p.date, p.name, p.thread, p.identity, \
-            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path \
-          FROM posts p \
-          LEFT JOIN images i ON p.image = i.id \
-          WHERE p.thread = $1 \
-          ORDER BY id ASC", thread_id)
+impl CatalogThread {
+    // threads whose opening post is held or shadow-banned only show up for its author and staff,
+    // the counts only include posts the viewer can see
+    // bump_locked goes by every post though, hidden ones count towards the bump limit too
+    // every open thread fits, stickies don't count towards max_threads, see Board::update_locks
+    pub async fn fetch(
+        pool: &PgPool,
+        board: &str,
+        sort: CatalogSort,
+        viewer: &Viewer,
+    ) -> Result<Vec<Self>> {
+        let res = sqlx::query_as!(
+            CatalogRow,
+            "SELECT t.id, t.last_updated, t.open, t.board, t.title, t.sticky, t.locked, \
+                op.date AS created, left(op.message, $4) AS excerpt, \
+                i.id as image_id, i.name as image_name, i.path as image_path, \
+                i.preview_path as image_preview_path, i.width as image_width, i.height as image_height, \
+                stats.reply_count, stats.image_count, stats.posters, stats.last_reply, \
+                stats.total_replies >= b.bump_limit AS bump_locked \
+            FROM threads t \
+            JOIN boards b ON b.code = t.board \
+            JOIN LATERAL ( \
+                SELECT * FROM posts WHERE thread = t.id ORDER BY id LIMIT 1 \
+            ) op ON true \
+            LEFT JOIN images i ON op.image = i.id \
+            JOIN LATERAL ( \
+                SELECT count(*) FILTER (WHERE visible AND id <> op.id) AS reply_count, \
+                    count(image) FILTER (WHERE visible AND id <> op.id) AS image_count, \
+                    count(DISTINCT identity) FILTER (WHERE visible) AS posters, \
+                    max(date) FILTER (WHERE visible AND id <> op.id) AS last_reply, \
+                    count(*) - 1 AS total_replies \
+                FROM ( \
+                    SELECT id, image, identity, date, \
+                        (pending = false AND shadow = false) OR identity = $2 OR $3 AS visible \
+                    FROM posts WHERE thread = t.id \
+                ) p \
+            ) stats ON true \
+            WHERE t.board = $1 AND t.open = true \
+            AND ((NOT op.pending AND NOT op.shadow) OR op.identity = $2 OR $3) \
+            ORDER BY t.sticky DESC, \
+                CASE WHEN $5 = 'created' THEN op.date END DESC, \
+                CASE WHEN $5 = 'replies' THEN stats.reply_count END DESC, \
+                CASE WHEN $5 = 'last_reply' THEN COALESCE(stats.last_reply, op.date) END DESC, \
+                t.last_updated DESC \
+            LIMIT (SELECT max_threads FROM boards WHERE code = $1) \
+                + (SELECT count(*) FROM threads WHERE board = $1 AND open AND sticky)",
+            board,
+            viewer.identity,
+            viewer.staff,
+            CATALOG_EXCERPT_LENGTH,
+            sort.name()
+        )
         .fetch_all(pool)
         .await?
         .into_iter()
-        .map(|pi| pi.into())
+        .map(|row| row.into())
         .collect();
 
         Ok(res)
     }
+}
 
-    pub async fn post(pool: &PgPool, post: &PostNew) -> Result<Self> {
-        let image = if let Some(image) = &post.image {
-            Some(Image::post(&pool, image).await?)
-        } else {
-            None
-        };
+struct IndexThreadRow {
+    id: i32,
+    last_updated: OffsetDateTime,
+    open: bool,
+    board: String,
+    title: String,
+    sticky: bool,
+    locked: bool,
+    post_count: i64,
+    image_count: i64,
+}
 
-        // sqlx can't deserialize rows if some optional struct fields aren't present
-        // so we have to add them to the query as NULL
-        // TODO may be there is or will be a better solution
-        let mut post: Post = sqlx::query_as!(
-            PostInner,
-            "INSERT INTO posts (thread, name, message, identity, image) \
-            VALUES ($1, $2, $3, $4, $5) \
-            RETURNING id, message, date, name, thread, identity, \
-            NULL::bigint as image_id, NULL as image_name, NULL as image_path, NULL as image_preview_path",
-            post.thread,
-            post.name,
-            post.message,
-            post.identity,
-            image.as_ref().map(|image| image.id)
+// a thread as shown on a board's index: the opening post, the last few replies
+// and counts of everything in between
+#[derive(Serialize)]
+pub struct IndexThread {
+    #[serde(flatten)]
+    thread: Thread,
+    post_count: i64,
+    image_count: i64,
+    omitted_posts: i64,
+    omitted_images: i64,
+    posts: Vec<Post>,
+}
+
+#[derive(Serialize)]
+pub struct BoardPage {
+    page: i64,
+    pages: i64,
+    threads: Vec<IndexThread>,
+}
+
+impl BoardPage {
+    // pages start at 0, three queries no matter how many threads or replies there are
+    // they share one snapshot, otherwise a bump in between could move a thread off the page
+    // after its counts were read, or make the counts and the posts disagree
+    pub async fn fetch(
+        pool: &PgPool,
+        board: &str,
+        page: i64,
+        per_page: i64,
+        replies: i64,
+        viewer: &Viewer,
+    ) -> Result<Self> {
+        let mut tx = pool.begin().await?;
+        sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut tx)
+            .await?;
+        let total = sqlx::query_as!(
+            PostCount,
+            "SELECT count(*) AS count FROM threads \
+            WHERE board = $1 AND open = true \
+            AND ( \
+                SELECT (NOT pending AND NOT shadow) OR identity = $2 OR $3 \
+                FROM posts WHERE thread = threads.id ORDER BY id LIMIT 1 \
+            )",
+            board,
+            viewer.identity,
+            viewer.staff
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        // both queries pick the page the same way the catalog does
+        let rows = sqlx::query_as!(
+            IndexThreadRow,
+            "WITH page AS ( \
+                SELECT * FROM threads \
+                WHERE board = $1 AND open = true \
+                AND ( \
+                    SELECT (NOT pending AND NOT shadow) OR identity = $2 OR $3 \
+                    FROM posts WHERE thread = threads.id ORDER BY id LIMIT 1 \
+                ) \
+                ORDER BY sticky DESC, last_updated DESC \
+                LIMIT $4 OFFSET $5 \
+            ) \
+            SELECT t.id, t.last_updated, t.open, t.board, t.title, t.sticky, t.locked, \
+                count(p.id) AS post_count, count(p.image) AS image_count \
+            FROM page t \
+            JOIN posts p ON p.thread = t.id \
+            AND ((p.pending = false AND p.shadow = false) OR p.identity = $2 OR $3) \
+            GROUP BY t.id, t.last_updated, t.open, t.board, t.title, t.sticky, t.locked \
+            ORDER BY t.sticky DESC, t.last_updated DESC",
+            board,
+            viewer.identity,
+            viewer.staff,
+            per_page,
+            page * per_page
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        let posts: Vec<Post> = sqlx::query_as!(PostInner, "\
+          WITH page AS ( \
+            SELECT id FROM threads \
+            WHERE board = $1 AND open = true \
+            AND ( \
+                SELECT (NOT pending AND NOT shadow) OR identity = $2 OR $3 \
+                FROM posts WHERE thread = threads.id ORDER BY id LIMIT 1 \
+            ) \
+            ORDER BY sticky DESC, last_updated DESC \
+            LIMIT $4 OFFSET $5 \
+          ), visible AS ( \
+            SELECT p.*, \
+              row_number() OVER (PARTITION BY p.thread ORDER BY p.id) AS from_start, \
+              row_number() OVER (PARTITION BY p.thread ORDER BY p.id DESC) AS from_end \
+            FROM posts p \
+            WHERE p.thread IN (SELECT id FROM page) \
+            AND ((p.pending = false AND p.shadow = false) OR p.identity = $2 OR $3) \
+          ) \
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM visible p \
+          LEFT JOIN images i ON p.image = i.id \
+          WHERE p.from_start = 1 OR p.from_end <= $6 \
+          ORDER BY p.id ASC",
+          board, viewer.identity, viewer.staff, per_page, page * per_page, replies)
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|pi| pi.into())
+        .collect();
+        tx.commit().await?;
+
+        let mut by_thread: HashMap<i32, Vec<Post>> = HashMap::new();
+        for post in posts {
+            by_thread.entry(post.thread).or_default().push(post);
+        }
+
+        let threads = rows
+            .into_iter()
+            .map(|row| {
+                let shown = by_thread.remove(&row.id).unwrap_or_default();
+                let shown_images = shown.iter().filter(|post| post.image.is_some()).count() as i64;
+                IndexThread {
+                    omitted_posts: row.post_count - shown.len() as i64,
+                    omitted_images: row.image_count - shown_images,
+                    post_count: row.post_count,
+                    image_count: row.image_count,
+                    thread: Thread {
+                        id: row.id,
+                        last_updated: row.last_updated,
+                        open: row.open,
+                        board: row.board,
+                        title: row.title,
+                        sticky: row.sticky,
+                        locked: row.locked,
+                    },
+                    posts: shown,
+                }
+            })
+            .collect();
+
+        Ok(BoardPage {
+            page,
+            pages: (total.count + per_page - 1) / per_page,
+            threads,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Post {
+    pub id: i64,
+    pub thread: i32,
+    pub board: String,
+    // counts up separately on every board, this is what >>links refer to
+    pub number: i64,
+    name: String,
+    timestamp: i64,
+    message: String,
+    image: Option<Image>,
+    pub pending: bool,
+    // shown under the post when its author was banned for it
+    pub ban_message: Option<String>,
+    #[serde(skip)]
+    identity: String,
+    #[serde(skip)]
+    pub shadow: bool,
+}
+
+struct PostInner {
+    id: i64,
+    thread: i32,
+    board: String,
+    number: i64,
+    name: String,
+    date: OffsetDateTime,
+    message: String,
+    identity: String,
+    pending: bool,
+    shadow: bool,
+    ban_message: Option<String>,
+    image_id: Option<i64>,
+    image_name: Option<String>,
+    image_path: Option<String>,
+    image_preview_path: Option<String>,
+    image_width: Option<i32>,
+    image_height: Option<i32>,
+}
+pub struct PostNew {
+    pub thread: i32,
+    pub name: String,
+    pub message: String,
+    pub identity: String,
+    pub image: Option<ImageNew>,
+    pub pending: bool,
+    pub shadow: bool,
+    // whether the board turns down content it has seen before, hashes are kept either way
+    pub r9k: bool,
+}
+impl From<PostInner> for Post {
+    fn from(pi: PostInner) -> Self {
+        Post {
+            id: pi.id,
+            thread: pi.thread,
+            board: pi.board,
+            number: pi.number,
+            name: pi.name,
+            timestamp: pi.date.timestamp(),
+            message: pi.message,
+            image: if let Some(image_id) = pi.image_id {
+                Some(Image {
+                    id: image_id,
+                    name: pi.image_name.unwrap(),
+                    path: pi.image_path.unwrap(),
+                    preview_path: pi.image_preview_path.unwrap(),
+                    width: pi.image_width,
+                    height: pi.image_height,
+                })
+            } else {
+                None
+            },
+            pending: pi.pending,
+            ban_message: pi.ban_message,
+            identity: pi.identity,
+            shadow: pi.shadow,
+        }
+    }
+}
+
+pub struct Author {
+    pub identity: String,
+    pub board: String,
+}
+
+struct PostCount {
+    count: i64,
+}
+
+pub struct DeletedPost {
+    pub id: i64,
+    pub thread: i32,
+    pub board: String,
+    // the whole thread went with it
+    pub opening: bool,
+    // uploads nothing points at anymore, for the caller to delete
+    pub files: Vec<String>,
+}
+
+struct DeletedRow {
+    id: i64,
+    thread: i32,
+    board: String,
+    opening: bool,
+    image: Option<i64>,
+}
+
+struct ImageFiles {
+    path: String,
+    preview_path: String,
+}
+
+// every file on disk that belonged to the images, previews can be the image itself
+fn image_files(images: Vec<ImageFiles>) -> Vec<String> {
+    let mut files = Vec::new();
+    for image in images {
+        if image.preview_path != image.path {
+            files.push(image.preview_path);
+        }
+        files.push(image.path);
+    }
+    files
+}
+
+struct PostImage {
+    image: Option<i64>,
+}
+
+struct FirstSeen {
+    first: Option<OffsetDateTime>,
+}
+
+impl Post {
+    // identities aren't stored anywhere on their own, so an identity is as old as its first post
+    pub async fn identity_first_seen(
+        pool: &PgPool,
+        identity: &str,
+    ) -> Result<Option<OffsetDateTime>> {
+        let seen = sqlx::query_as!(
+            FirstSeen,
+            "SELECT min(date) AS first FROM posts WHERE identity = $1",
+            identity
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(seen.first)
+    }
+
+    pub async fn fetch_for_thread(
+        pool: &PgPool,
+        thread_id: i32,
+        viewer: &Viewer,
+    ) -> Result<Vec<Self>> {
+        let res = sqlx::query_as!(PostInner, "\
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM posts p \
+          LEFT JOIN images i ON p.image = i.id \
+          WHERE p.thread = $1 \
+          AND ((p.pending = false AND p.shadow = false) OR p.identity = $2 OR $3) \
+          ORDER BY id ASC", thread_id, viewer.identity, viewer.staff)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|pi| pi.into())
+        .collect();
+
+        Ok(res)
+    }
+
+    // what went out to the thread after post `seen`, in the order it went out, approved posts
+    // keep their old ids so going by id would miss them
+    // None if the events don't go back that far anymore, see StoredEvent::delete_old
+    pub async fn fetch_published_after(
+        pool: &PgPool,
+        thread_id: i32,
+        seen: i64,
+        viewer: &Viewer,
+    ) -> Result<Option<Vec<Self>>> {
+        let since = sqlx::query_as!(
+            LastEventId,
+            "SELECT min(id) AS id FROM events WHERE thread = $1 AND kind = 'post' AND post = $2",
+            thread_id,
+            seen
+        )
+        .fetch_one(pool)
+        .await?;
+        let since = match since.id {
+            Some(since) => since,
+            None => return Ok(None),
+        };
+
+        let res = sqlx::query_as!(PostInner, "\
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM posts p \
+          JOIN ( \
+            SELECT post, max(id) AS event FROM events \
+            WHERE thread = $1 AND kind = 'post' AND id > $4 \
+            GROUP BY post \
+          ) e ON e.post = p.id \
+          LEFT JOIN images i ON p.image = i.id \
+          WHERE p.thread = $1 \
+          AND ((p.pending = false AND p.shadow = false) OR p.identity = $2 OR $3) \
+          ORDER BY e.event ASC", thread_id, viewer.identity, viewer.staff, since)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|pi| pi.into())
+        .collect();
+
+        Ok(Some(res))
+    }
+
+    pub async fn fetch_by_number(
+        pool: &PgPool,
+        board: &str,
+        number: i64,
+        viewer: &Viewer,
+    ) -> Result<Option<Self>> {
+        let post = sqlx::query_as!(PostInner, "\
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM posts p \
+          LEFT JOIN images i ON p.image = i.id \
+          WHERE p.board = $1 AND p.number = $2 \
+          AND ((p.pending = false AND p.shadow = false) OR p.identity = $3 OR $4)",
+          board, number, viewer.identity, viewer.staff)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(post.map(|pi| pi.into()))
+    }
+
+    pub fn visible_to(&self, viewer: &Viewer) -> bool {
+        (!self.pending && !self.shadow)
+            || viewer.staff
+            || viewer.identity.as_deref() == Some(self.identity.as_str())
+    }
+
+    pub async fn fetch_author(pool: &PgPool, post_id: i64) -> Result<Option<Author>> {
+        sqlx::query_as!(
+            Author,
+            "SELECT identity, board FROM posts WHERE id = $1",
+            post_id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    // >>N links in the message, in order and without duplicates
+    pub fn quotes(&self) -> Vec<i64> {
+        let mut quotes = Vec::new();
+        for part in self.message.split(">>").skip(1) {
+            let digits: String = part.chars().take_while(|c| c.is_ascii_digit()).collect();
+            if let Ok(number) = digits.parse() {
+                if !quotes.contains(&number) && quotes.len() < MAX_QUOTES {
+                    quotes.push(number);
+                }
+            }
+        }
+        quotes
+    }
+
+    // who wrote the posts this one quotes, with the numbers of their posts, see Reply::record
+    pub async fn quoted_authors(&self, pool: &PgPool) -> Result<Vec<(String, Vec<i64>)>> {
+        let rows = sqlx::query_as!(
+            QuotedAuthor,
+            "SELECT r.identity, q.number FROM replies r \
+            JOIN posts q ON q.id = r.quoted \
+            WHERE r.post = $1 \
+            ORDER BY r.id",
+            self.id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut authors: Vec<(String, Vec<i64>)> = Vec::new();
+        for row in rows {
+            match authors
+                .iter_mut()
+                .find(|(author, _)| *author == row.identity)
+            {
+                Some((_, numbers)) => numbers.push(row.number),
+                None => authors.push((row.identity, vec![row.number])),
+            }
+        }
+        Ok(authors)
+    }
+
+    // held posts don't count, otherwise waiting out premod would only take a held post
+    pub async fn identity_first_approved(
+        pool: &PgPool,
+        identity: &str,
+    ) -> Result<Option<OffsetDateTime>> {
+        let seen = sqlx::query_as!(
+            FirstSeen,
+            "SELECT min(date) AS first FROM posts WHERE identity = $1 AND pending = false",
+            identity
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(seen.first)
+    }
+
+    pub async fn approved_count(pool: &PgPool, identity: &str) -> Result<i64> {
+        let count = sqlx::query_as!(
+            PostCount,
+            "SELECT count(*) AS count FROM posts WHERE identity = $1 AND pending = false",
+            identity
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(count.count)
+    }
+
+    pub async fn fetch_pending(pool: &PgPool, board: &str) -> Result<Vec<Self>> {
+        let res = sqlx::query_as!(PostInner, "\
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM posts p \
+          JOIN threads t ON p.thread = t.id \
+          LEFT JOIN images i ON p.image = i.id \
+          WHERE t.board = $1 AND p.pending = true \
+          ORDER BY id ASC", board)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|pi| pi.into())
+        .collect();
+
+        Ok(res)
+    }
+
+    // no visibility checks, callers have to filter with visible_to
+    pub async fn fetch(pool: &PgPool, post_id: i64) -> Result<Option<Self>> {
+        let post = sqlx::query_as!(PostInner, "\
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM posts p \
+          LEFT JOIN images i ON p.image = i.id \
+          WHERE p.id = $1", post_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(post.map(|pi| pi.into()))
+    }
+
+    // None if there's no such post or it isn't pending
+    // the thread is bumped as if the post had just gone in
+    pub async fn approve(pool: &PgPool, post_id: i64) -> Result<Option<Self>> {
+        let mut tx = pool.begin().await?;
+        let post = sqlx::query_as!(PostInner, "\
+          WITH p AS ( \
+            UPDATE posts SET pending = false \
+            WHERE id = $1 AND pending = true \
+            RETURNING * \
+          ) \
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM p \
+          LEFT JOIN images i ON p.image = i.id", post_id)
+        .fetch_optional(&mut tx)
+        .await?;
+        let post: Self = match post {
+            Some(post) => post.into(),
+            None => return Ok(None),
+        };
+        if !post.shadow {
+            Thread::bump(&mut tx, post.thread).await?;
+        }
+        tx.commit().await?;
+
+        Ok(Some(post))
+    }
+
+    pub async fn edit(pool: &PgPool, post_id: i64, message: &str) -> Result<Option<Self>> {
+        let post = sqlx::query_as!(PostInner, "\
+          WITH p AS ( \
+            UPDATE posts SET message = $2 \
+            WHERE id = $1 \
+            RETURNING * \
+          ) \
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM p \
+          LEFT JOIN images i ON p.image = i.id", post_id, message)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(post.map(|pi| pi.into()))
+    }
+
+    // None if there's no such post or it has no image
+    // the image goes for good, its files are returned for the caller to delete
+    pub async fn remove_image(pool: &PgPool, post_id: i64) -> Result<Option<(Self, Vec<String>)>> {
+        let mut tx = pool.begin().await?;
+        let image = sqlx::query_as!(
+            PostImage,
+            "SELECT image FROM posts WHERE id = $1 AND image IS NOT NULL FOR UPDATE",
+            post_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let image = match image.and_then(|post| post.image) {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+
+        let post = sqlx::query_as!(PostInner, "\
+          WITH p AS ( \
+            UPDATE posts SET image = NULL \
+            WHERE id = $1 AND image IS NOT NULL \
+            RETURNING * \
+          ) \
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            NULL::bigint as image_id, NULL as image_name, NULL as image_path, NULL as image_preview_path, \
+            NULL::int as image_width, NULL::int as image_height \
+          FROM p", post_id)
+        .fetch_one(&mut tx)
+        .await?;
+        let images = sqlx::query_as!(
+            ImageFiles,
+            "DELETE FROM images WHERE id = $1 RETURNING path, preview_path",
+            image
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(Some((post.into(), image_files(images))))
+    }
+
+    pub async fn set_ban_message(
+        pool: &PgPool,
+        post_id: i64,
+        message: &str,
+    ) -> Result<Option<Self>> {
+        let post = sqlx::query_as!(PostInner, "\
+          WITH p AS ( \
+            UPDATE posts SET ban_message = $2 \
+            WHERE id = $1 \
+            RETURNING * \
+          ) \
+          SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM p \
+          LEFT JOIN images i ON p.image = i.id", post_id, message)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(post.map(|pi| pi.into()))
+    }
+
+    // only pending posts, None for anything else
+    pub async fn reject(pool: &PgPool, post_id: i64) -> Result<Option<DeletedPost>> {
+        Self::remove(pool, post_id, true).await
+    }
+
+    pub async fn delete(pool: &PgPool, post_id: i64) -> Result<Option<DeletedPost>> {
+        Self::remove(pool, post_id, false).await
+    }
+
+    // deleting the opening post takes the whole thread with it
+    async fn remove(
+        pool: &PgPool,
+        post_id: i64,
+        pending_only: bool,
+    ) -> Result<Option<DeletedPost>> {
+        let mut tx = pool.begin().await?;
+        let deleted = sqlx::query_as!(
+            DeletedRow,
+            "DELETE FROM posts d WHERE d.id = $1 AND (d.pending = true OR NOT $2) \
+            RETURNING d.id, d.thread, d.board, d.image, \
+            NOT EXISTS (SELECT 1 FROM posts p WHERE p.thread = d.thread AND p.id < d.id) AS opening",
+            post_id,
+            pending_only
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let deleted = match deleted {
+            Some(deleted) => deleted,
+            None => return Ok(None),
+        };
+
+        let mut images = match deleted.image {
+            Some(image) => {
+                sqlx::query_as!(
+                    ImageFiles,
+                    "DELETE FROM images WHERE id = $1 RETURNING path, preview_path",
+                    image
+                )
+                .fetch_all(&mut tx)
+                .await?
+            }
+            None => Vec::new(),
+        };
+        if deleted.opening {
+            let replies = sqlx::query_as!(
+                ImageFiles,
+                "WITH deleted AS ( \
+                    DELETE FROM posts WHERE thread = $1 RETURNING image \
+                ) \
+                DELETE FROM images WHERE id IN (SELECT image FROM deleted) \
+                RETURNING path, preview_path",
+                deleted.thread
+            )
+            .fetch_all(&mut tx)
+            .await?;
+            images.extend(replies);
+            sqlx::query!("DELETE FROM threads WHERE id = $1", deleted.thread)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        let files = image_files(images);
+        Ok(Some(DeletedPost {
+            id: deleted.id,
+            thread: deleted.thread,
+            board: deleted.board,
+            opening: deleted.opening,
+            files,
+        }))
+    }
+
+    pub async fn post(pool: &PgPool, post: &PostNew) -> Result<Posted<Self>> {
+        let mut tx = pool.begin().await?;
+        let posted = Post::insert(&mut tx, post).await?;
+        match posted {
+            Posted::Done(_) => tx.commit().await?,
+            Posted::Duplicate(_) => tx.rollback().await?,
+        }
+        Ok(posted)
+    }
+
+    // everything that goes with a new post, the r9k hashes are claimed here so two posts
+    // with the same content can't both get in
+    async fn insert(tx: &mut Tx, post: &PostNew) -> Result<Posted<Self>> {
+        let enforce = post.r9k;
+        let mut hashes = Vec::with_capacity(2);
+        hashes.extend(r9k::message_hash(&post.message).map(|hash| (R9kKind::Message, hash)));
+        hashes.extend(
+            post.image
+                .as_ref()
+                .map(|image| (R9kKind::File, image.hash.clone())),
+        );
+
+        let image = if let Some(image) = &post.image {
+            Some(Image::post(tx, image).await?)
+        } else {
+            None
+        };
+
+        // sqlx can't deserialize rows if some optional struct fields aren't present
+        // so we have to add them to the query as NULL
+        // TODO may be there is or will be a better solution
+        //
+        // the counter update and the insert are one statement, the row lock on the board
+        // keeps concurrent posts from getting the same number and a failed insert takes
+        // the increment back with it
+        let mut post: Post = sqlx::query_as!(
+            PostInner,
+            "WITH counter AS ( \
+                UPDATE boards SET post_counter = post_counter + 1 \
+                WHERE code = (SELECT board FROM threads WHERE id = $1) \
+                RETURNING code, post_counter \
+            ) \
+            INSERT INTO posts (thread, board, number, name, message, identity, image, pending, shadow) \
+            SELECT $1, code, post_counter, $2, $3, $4, $5, $6, $7 FROM counter \
+            RETURNING id, message, date, name, thread, board, number, identity, pending, shadow, ban_message, \
+            NULL::bigint as image_id, NULL as image_name, NULL as image_path, NULL as image_preview_path, \
+            NULL::int as image_width, NULL::int as image_height",
+            post.thread,
+            post.name,
+            post.message,
+            post.identity,
+            image.as_ref().map(|image| image.id),
+            post.pending,
+            post.shadow
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .into();
+
+        post.image = image;
+
+        // a shadowed post's hashes would get other people's posts turned down for content
+        // they can't see, so those are only checked
+        for (kind, hash) in hashes.iter() {
+            let fresh = if post.shadow {
+                !R9k::seen(tx, &post.board, *kind, hash).await?
+            } else {
+                R9k::claim(tx, &post.board, *kind, hash).await?
+            };
+            if !fresh && enforce {
+                return Ok(Posted::Duplicate(*kind));
+            }
+        }
+
+        if !post.pending && !post.shadow {
+            Thread::bump(tx, post.thread).await?;
+        }
+
+        Reply::record(tx, &post).await?;
+
+        Ok(Posted::Done(post))
+    }
+}
+
+struct QuotedAuthor {
+    identity: String,
+    number: i64,
+}
+
+struct ReplyRow {
+    reply_id: i64,
+    quoted: i64,
+    read: bool,
+    id: i64,
+    thread: i32,
+    board: String,
+    number: i64,
+    name: String,
+    date: OffsetDateTime,
+    message: String,
+    identity: String,
+    pending: bool,
+    shadow: bool,
+    ban_message: Option<String>,
+    image_id: Option<i64>,
+    image_name: Option<String>,
+    image_path: Option<String>,
+    image_preview_path: Option<String>,
+    image_width: Option<i32>,
+    image_height: Option<i32>,
+}
+
+// a post quoting one of yours, there's one for every post of yours it quotes
+#[derive(Serialize)]
+pub struct Reply {
+    pub id: i64,
+    // the number of your post
+    pub quoted: i64,
+    pub read: bool,
+    pub post: Post,
+}
+
+impl From<ReplyRow> for Reply {
+    fn from(row: ReplyRow) -> Self {
+        Reply {
+            id: row.reply_id,
+            quoted: row.quoted,
+            read: row.read,
+            post: PostInner {
+                id: row.id,
+                thread: row.thread,
+                board: row.board,
+                number: row.number,
+                name: row.name,
+                date: row.date,
+                message: row.message,
+                identity: row.identity,
+                pending: row.pending,
+                shadow: row.shadow,
+                ban_message: row.ban_message,
+                image_id: row.image_id,
+                image_name: row.image_name,
+                image_path: row.image_path,
+                image_preview_path: row.image_preview_path,
+                image_width: row.image_width,
+                image_height: row.image_height,
+            }
+            .into(),
+        }
+    }
+}
+
+impl Reply {
+    // quotes of your own posts don't count, quotes in held posts are recorded but
+    // only show up once the post is approved
+    pub async fn record(tx: &mut Tx, post: &Post) -> Result<()> {
+        for number in post.quotes() {
+            sqlx::query!(
+                "INSERT INTO replies (identity, post, quoted) \
+                SELECT q.identity, $1, q.id FROM posts q \
+                WHERE q.board = $2 AND q.number = $3 AND q.identity <> $4 \
+                ON CONFLICT DO NOTHING",
+                post.id,
+                post.board,
+                number,
+                post.identity
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    // newest first, replies in posts nobody else can see are left out
+    pub async fn fetch_unread(pool: &PgPool, identity: &str, limit: i64) -> Result<Vec<Self>> {
+        let res = sqlx::query_as!(ReplyRow, "\
+          SELECT r.id AS reply_id, q.number AS quoted, r.read, \
+            p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+            i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+            i.width as image_width, i.height as image_height \
+          FROM replies r \
+          JOIN posts p ON p.id = r.post \
+          JOIN posts q ON q.id = r.quoted \
+          LEFT JOIN images i ON p.image = i.id \
+          WHERE r.identity = $1 AND r.read = false \
+          AND p.pending = false AND p.shadow = false \
+          ORDER BY r.id DESC \
+          LIMIT $2", identity, limit)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| row.into())
+        .collect();
+
+        Ok(res)
+    }
+
+    // everything up to and including `until`, or all of them
+    pub async fn mark_read(pool: &PgPool, identity: &str, until: Option<i64>) -> Result<u64> {
+        sqlx::query!(
+            "UPDATE replies SET read = true \
+            WHERE identity = $1 AND read = false AND ($2::bigint IS NULL OR id <= $2)",
+            identity,
+            until
+        )
+        .execute(pool)
+        .await
+    }
+}
+
+struct Watcher {
+    identity: String,
+}
+
+// counts are of public posts after the last one seen, your own posts don't count
+#[derive(Serialize)]
+pub struct WatchedThread {
+    pub thread: i32,
+    pub board: String,
+    pub title: String,
+    pub last_seen: i64,
+    pub unread: i64,
+    // unread posts quoting yours
+    pub you: i64,
+}
+
+// threads an identity follows, archived and deleted ones are dropped by delete_stale
+pub struct Watch;
+impl Watch {
+    // watching a thread again marks everything in it as seen
+    // false if the thread doesn't exist or is archived
+    pub async fn post(pool: &PgPool, identity: &str, thread_id: i32) -> Result<bool> {
+        let watched = sqlx::query!(
+            "INSERT INTO watches (identity, thread, last_seen) \
+            SELECT $1, t.id, COALESCE((SELECT max(id) FROM posts WHERE thread = t.id), 0) \
+            FROM threads t WHERE t.id = $2 AND t.open = true \
+            ON CONFLICT (identity, thread) DO UPDATE SET last_seen = EXCLUDED.last_seen",
+            identity,
+            thread_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(watched > 0)
+    }
+    pub async fn delete(pool: &PgPool, identity: &str, thread_id: i32) -> Result<bool> {
+        let deleted = sqlx::query!(
+            "DELETE FROM watches WHERE identity = $1 AND thread = $2",
+            identity,
+            thread_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(deleted > 0)
+    }
+    pub async fn count(pool: &PgPool, identity: &str) -> Result<i64> {
+        let count = sqlx::query_as!(
+            PostCount,
+            "SELECT count(*) AS count FROM watches WHERE identity = $1",
+            identity
         )
         .fetch_one(pool)
-        .await?
-        .into();
+        .await?;
+        Ok(count.count)
+    }
+    pub async fn fetch_all(pool: &PgPool, identity: &str) -> Result<Vec<WatchedThread>> {
+        sqlx::query_as!(
+            WatchedThread,
+            "SELECT t.id AS thread, t.board, t.title, w.last_seen, \
+                (SELECT count(*) FROM posts p \
+                    WHERE p.thread = t.id AND p.id > w.last_seen AND p.identity <> w.identity \
+                    AND p.pending = false AND p.shadow = false) AS unread, \
+                (SELECT count(DISTINCT r.post) FROM replies r JOIN posts p ON p.id = r.post \
+                    WHERE r.identity = w.identity AND p.thread = t.id AND p.id > w.last_seen \
+                    AND p.pending = false AND p.shadow = false) AS you \
+            FROM watches w \
+            JOIN threads t ON t.id = w.thread AND t.open = true \
+            WHERE w.identity = $1 \
+            ORDER BY t.last_updated DESC",
+            identity
+        )
+        .fetch_all(pool)
+        .await
+    }
+    // everyone who should hear about a new post, apart from its author
+    pub async fn watchers_of(pool: &PgPool, post: &Post) -> Result<Vec<String>> {
+        let watchers = sqlx::query_as!(
+            Watcher,
+            "SELECT identity FROM watches WHERE thread = $1 AND identity <> $2",
+            post.thread,
+            post.identity
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(watchers
+            .into_iter()
+            .map(|watcher| watcher.identity)
+            .collect())
+    }
+    pub async fn watchers(pool: &PgPool, thread_id: i32) -> Result<Vec<String>> {
+        let watchers = sqlx::query_as!(
+            Watcher,
+            "SELECT identity FROM watches WHERE thread = $1",
+            thread_id
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(watchers
+            .into_iter()
+            .map(|watcher| watcher.identity)
+            .collect())
+    }
+    // watches outlive their threads for a while so watchers can be told they're gone
+    pub async fn delete_stale(pool: &PgPool) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM watches w WHERE NOT EXISTS ( \
+                SELECT 1 FROM threads t WHERE t.id = w.thread AND t.open = true \
+            )"
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
 
-        post.image = image;
+// results are ordered by rank and then id, so that's what a cursor has to remember
+pub struct SearchCursor {
+    rank: f32,
+    id: i64,
+}
 
-        Ok(post)
+impl SearchCursor {
+    pub fn parse(cursor: &str) -> Option<Self> {
+        let mut parts = cursor.splitn(2, '_');
+        let rank = parts.next()?.parse().ok()?;
+        let id = parts.next()?.parse().ok()?;
+        Some(SearchCursor { rank, id })
     }
 }
 
+impl std::fmt::Display for SearchCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}_{}", self.rank, self.id)
+    }
+}
+
+// None means no filter, timestamps are in seconds
+pub struct SearchFilter {
+    pub query: String,
+    pub board: Option<String>,
+    pub thread: Option<i32>,
+    pub has_image: Option<bool>,
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub archived: Option<bool>,
+}
+
+struct SearchRow {
+    id: i64,
+    thread: i32,
+    board: String,
+    number: i64,
+    name: String,
+    date: OffsetDateTime,
+    message: String,
+    identity: String,
+    pending: bool,
+    shadow: bool,
+    ban_message: Option<String>,
+    image_id: Option<i64>,
+    image_name: Option<String>,
+    image_path: Option<String>,
+    image_preview_path: Option<String>,
+    image_width: Option<i32>,
+    image_height: Option<i32>,
+    title: String,
+    open: bool,
+    rank: f32,
+    snippet: String,
+    title_snippet: String,
+}
+
 #[derive(Serialize)]
+pub struct SearchResult {
+    post: Post,
+    title: String,
+    archived: bool,
+    rank: f32,
+    // html, the message is escaped before the matches get wrapped in <mark>
+    snippet: String,
+    // the same for the title, which the opening post matches on too
+    title_snippet: String,
+}
+
+impl From<SearchRow> for SearchResult {
+    fn from(row: SearchRow) -> Self {
+        SearchResult {
+            post: PostInner {
+                id: row.id,
+                thread: row.thread,
+                board: row.board,
+                number: row.number,
+                name: row.name,
+                date: row.date,
+                message: row.message,
+                identity: row.identity,
+                pending: row.pending,
+                shadow: row.shadow,
+                ban_message: row.ban_message,
+                image_id: row.image_id,
+                image_name: row.image_name,
+                image_path: row.image_path,
+                image_preview_path: row.image_preview_path,
+                image_width: row.image_width,
+                image_height: row.image_height,
+            }
+            .into(),
+            title: row.title,
+            archived: !row.open,
+            rank: row.rank,
+            snippet: row.snippet,
+            title_snippet: row.title_snippet,
+        }
+    }
+}
+
+impl SearchResult {
+    pub fn cursor(&self) -> SearchCursor {
+        SearchCursor {
+            rank: self.rank,
+            id: self.post.id,
+        }
+    }
+
+    // archived threads are searched too unless the filter says otherwise
+    // the snippets are only built for the page that's returned
+    pub async fn search(
+        pool: &PgPool,
+        filter: &SearchFilter,
+        cursor: Option<&SearchCursor>,
+        limit: i64,
+        viewer: &Viewer,
+    ) -> Result<Vec<Self>> {
+        let res = sqlx::query_as!(SearchRow, "\
+          SELECT r.id, r.thread, r.board, r.number, r.name, r.date, r.message, r.identity, \
+            r.pending, r.shadow, r.ban_message, r.image_id, r.image_name, r.image_path, r.image_preview_path, \
+            r.image_width, r.image_height, r.title, r.open, r.rank, \
+            ts_headline('english', \
+              replace(replace(replace(r.message, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), \
+              websearch_to_tsquery('english', $1), \
+              'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=20, MinWords=5' \
+            ) AS snippet, \
+            ts_headline('english', \
+              replace(replace(replace(r.title, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), \
+              websearch_to_tsquery('english', $1), \
+              'StartSel=<mark>, StopSel=</mark>, HighlightAll=true' \
+            ) AS title_snippet \
+          FROM ( \
+            SELECT p.id, p.message, p.date, p.name, p.thread, p.board, p.number, p.identity, p.pending, p.shadow, p.ban_message, \
+              i.id as image_id, i.name as image_name,i.path as image_path,i.preview_path as image_preview_path, \
+              i.width as image_width, i.height as image_height, \
+              t.title, t.open, ts_rank(p.search, q.query) AS rank \
+            FROM posts p \
+            JOIN threads t ON p.thread = t.id \
+            LEFT JOIN images i ON p.image = i.id \
+            CROSS JOIN websearch_to_tsquery('english', $1) q(query) \
+            WHERE p.search @@ q.query \
+            AND ((p.pending = false AND p.shadow = false) OR p.identity = $2 OR $3) \
+            AND ($4::text IS NULL OR p.board = $4) \
+            AND ($5::int IS NULL OR p.thread = $5) \
+            AND ($6::bool IS NULL OR (p.image IS NOT NULL) = $6) \
+            AND ($7::bigint IS NULL OR p.date < to_timestamp($7)) \
+            AND ($8::bigint IS NULL OR p.date > to_timestamp($8)) \
+            AND ($9::bool IS NULL OR t.open <> $9) \
+            AND ($10::real IS NULL OR (ts_rank(p.search, q.query), p.id) < ($10, $11)) \
+            ORDER BY rank DESC, p.id DESC \
+            LIMIT $12 \
+          ) r \
+          ORDER BY r.rank DESC, r.id DESC",
+          filter.query,
+          viewer.identity,
+          viewer.staff,
+          filter.board,
+          filter.thread,
+          filter.has_image,
+          filter.before,
+          filter.after,
+          filter.archived,
+          cursor.map(|cursor| cursor.rank),
+          cursor.map(|cursor| cursor.id),
+          limit)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| row.into())
+        .collect();
+
+        Ok(res)
+    }
+}
+
+pub enum EventKind {
+    Post,
+    Delete,
+    Lock,
+    Sticky,
+    // only ever published by Board::update_locks
+    Archive,
+    Edit,
+    RemoveImage,
+    BanMessage,
+    // published on the board the thread left
+    Move,
+}
+
+impl EventKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Post => "post",
+            Self::Delete => "delete",
+            Self::Lock => "lock",
+            Self::Sticky => "sticky",
+            Self::Archive => "archive",
+            Self::Edit => "edit",
+            Self::RemoveImage => "remove_image",
+            Self::BanMessage => "ban_message",
+            Self::Move => "move",
+        }
+    }
+}
+
+// instances LISTEN on this to find out about boards created elsewhere
+pub const BOARDS_CHANNEL: &str = "boards";
+
+const BOARD_CHANNEL_PREFIX: &str = "board_";
+// first half of the advisory lock key publishing takes, the board's hash is the second
+const EVENTS_LOCK: i32 = 1;
+
+pub fn board_channel(board: &str) -> String {
+    format!("{}{}", BOARD_CHANNEL_PREFIX, board)
+}
+
+// the board a board_channel is for
+pub fn channel_board(channel: &str) -> Option<&str> {
+    let board = channel.get(BOARD_CHANNEL_PREFIX.len()..)?;
+    Some(board).filter(|_| channel.starts_with(BOARD_CHANNEL_PREFIX))
+}
+
+struct LastEventId {
+    board: String,
+    id: Option<i64>,
+}
+
+// events go through postgres so every instance sees them, see util::notify
+pub struct StoredEvent {
+    pub id: i64,
+    pub board: String,
+    pub thread: i32,
+    kind: String,
+    pub post: Option<i64>,
+}
+
+impl StoredEvent {
+    pub fn kind(&self) -> Option<EventKind> {
+        match self.kind.as_str() {
+            "post" => Some(EventKind::Post),
+            "delete" => Some(EventKind::Delete),
+            "lock" => Some(EventKind::Lock),
+            "sticky" => Some(EventKind::Sticky),
+            "archive" => Some(EventKind::Archive),
+            "edit" => Some(EventKind::Edit),
+            "remove_image" => Some(EventKind::RemoveImage),
+            "ban_message" => Some(EventKind::BanMessage),
+            "move" => Some(EventKind::Move),
+            _ => None,
+        }
+    }
+
+    // the notification goes out when the transaction commits, after the event is visible
+    // a board's events are inserted one at a time so its ids become visible in order,
+    // listeners rely on that, events on different boards don't wait for each other
+    pub async fn publish(
+        pool: &PgPool,
+        board: &str,
+        thread: i32,
+        kind: EventKind,
+        post: Option<i64>,
+    ) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        Self::lock(&mut tx, board).await?;
+        sqlx::query!(
+            "WITH e AS ( \
+                INSERT INTO events (board, thread, kind, post) \
+                VALUES ($1, $2, $3, $4) \
+                RETURNING id, board \
+            ) \
+            SELECT pg_notify('board_' || board, id::text) FROM e",
+            board,
+            thread,
+            kind.name(),
+            post
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+    // held until the transaction ends, readers aren't blocked
+    // boards whose names hash the same share a lock, that only costs them some waiting
+    async fn lock(tx: &mut Tx, board: &str) -> Result<()> {
+        sqlx::query!(
+            "SELECT 1 AS locked FROM pg_advisory_xact_lock($1, hashtext($2))",
+            EVENTS_LOCK,
+            board
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+    pub async fn announce_board(pool: &PgPool, board: &str) -> Result<()> {
+        sqlx::query!("SELECT pg_notify($1, $2)", BOARDS_CHANNEL, board)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+    // per board, boards without events aren't in it
+    pub async fn last_ids(pool: &PgPool) -> Result<HashMap<String, i64>> {
+        let last = sqlx::query_as!(
+            LastEventId,
+            "SELECT board, max(id) AS id FROM events GROUP BY board"
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(last
+            .into_iter()
+            .filter_map(|last| Some((last.board, last.id?)))
+            .collect())
+    }
+    pub async fn fetch_after(pool: &PgPool, board: &str, after: i64) -> Result<Vec<Self>> {
+        sqlx::query_as!(
+            StoredEvent,
+            "SELECT id, board, thread, kind, post FROM events \
+            WHERE board = $1 AND id > $2 ORDER BY id",
+            board,
+            after
+        )
+        .fetch_all(pool)
+        .await
+    }
+    // listeners only ever need what they missed while reconnecting
+    pub async fn delete_old(pool: &PgPool) -> Result<()> {
+        sqlx::query!("DELETE FROM events WHERE created < now() - interval '1 hour'")
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+struct Exists {
+    exists: bool,
+}
+
+struct MutedUntil {
+    muted_until: OffsetDateTime,
+}
+
+// what a hash was taken of, a message can never be a duplicate of a file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum R9kKind {
+    Message,
+    File,
+}
+
+impl R9kKind {
+    // also the form field a duplicate gets reported on
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Message => "message",
+            Self::File => "file",
+        }
+    }
+}
+
+// content hashes for boards in r9k mode, see util::r9k
+pub struct R9k;
+impl R9k {
+    // false if the board had it already, a post claiming the same hash at the same time
+    // waits on the unique index until this transaction is done
+    async fn claim(tx: &mut Tx, board: &str, kind: R9kKind, hash: &[u8]) -> Result<bool> {
+        let claimed = sqlx::query!(
+            "INSERT INTO r9k_hashes (board, kind, hash) VALUES ($1, $2, $3) \
+            ON CONFLICT DO NOTHING \
+            RETURNING hash",
+            board,
+            kind.name(),
+            hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        Ok(claimed.is_some())
+    }
+    async fn seen(tx: &mut Tx, board: &str, kind: R9kKind, hash: &[u8]) -> Result<bool> {
+        let seen = sqlx::query_as!(
+            Exists,
+            "SELECT EXISTS ( \
+                SELECT 1 FROM r9k_hashes WHERE board = $1 AND kind = $2 AND hash = $3 \
+            ) AS exists",
+            board,
+            kind.name(),
+            hash
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        Ok(seen.exists)
+    }
+    pub async fn muted_until(
+        pool: &PgPool,
+        board: &str,
+        identity: &str,
+    ) -> Result<Option<OffsetDateTime>> {
+        let mute = sqlx::query_as!(
+            MutedUntil,
+            "SELECT muted_until FROM r9k_mutes \
+            WHERE board = $1 AND identity = $2 AND muted_until > now()",
+            board,
+            identity
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(mute.map(|mute| mute.muted_until))
+    }
+    // every violation doubles the mute, up to 2^16 times the base length
+    pub async fn mute(
+        pool: &PgPool,
+        board: &str,
+        identity: &str,
+        seconds: i32,
+    ) -> Result<OffsetDateTime> {
+        let mute = sqlx::query_as!(
+            MutedUntil,
+            "INSERT INTO r9k_mutes (board, identity, violations, muted_until) \
+            VALUES ($1, $2, 1, now() + make_interval(secs => $3)) \
+            ON CONFLICT (board, identity) DO UPDATE \
+            SET violations = r9k_mutes.violations + 1, \
+            muted_until = now() + make_interval( \
+                secs => $3 * power(2, LEAST(r9k_mutes.violations, 16)) \
+            ) \
+            RETURNING muted_until",
+            board,
+            identity,
+            seconds as f64
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(mute.muted_until)
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct Image {
     id: i64,
     name: String,
     path: String,
     preview_path: String,
+    width: Option<i32>,
+    height: Option<i32>,
 }
 
 pub struct ImageNew {
     pub name: String,
     pub path: String,
     pub preview_path: String,
+    pub hash: Vec<u8>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
 }
 impl Image {
     pub async fn fetch(pool: &PgPool, id: i64) -> Result<Option<Self>> {
-        sqlx::query_as!(Self, "SELECT * FROM images WHERE id = $1", id)
-            .fetch_optional(pool)
-            .await
+        sqlx::query_as!(
+            Self,
+            "SELECT id, name, path, preview_path, width, height FROM images WHERE id = $1",
+            id
+        )
+        .fetch_optional(pool)
+        .await
     }
 
-    pub async fn post(pool: &PgPool, image: &ImageNew) -> Result<Self> {
+    pub async fn post(tx: &mut Tx, image: &ImageNew) -> Result<Self> {
         sqlx::query_as!(
             Image,
-            "INSERT INTO images  (name, path, preview_path) \
-            VALUES ($1, $2, $3) \
-            RETURNING id, name, path, preview_path",
+            "INSERT INTO images  (name, path, preview_path, hash, width, height) \
+            VALUES ($1, $2, $3, $4, $5, $6) \
+            RETURNING id, name, path, preview_path, width, height",
             image.name,
             image.path,
-            image.preview_path
+            image.preview_path,
+            image.hash,
+            image.width,
+            image.height
         )
-        .fetch_one(pool)
+        .fetch_one(&mut *tx)
         .await
     }
 }
+
+// a public post in thread 1 on /b/, for tests elsewhere that need one
+#[cfg(test)]
+impl Post {
+    pub fn published(id: i64, identity: &str, message: &str) -> Self {
+        Post {
+            id,
+            thread: 1,
+            board: "b".to_owned(),
+            number: id,
+            name: "Anonymous".to_owned(),
+            timestamp: 0,
+            message: message.to_owned(),
+            image: None,
+            pending: false,
+            ban_message: None,
+            identity: identity.to_owned(),
+            shadow: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(message: &str) -> Post {
+        Post::published(1, "someone", message)
+    }
+
+    #[test]
+    fn quotes_in_order() {
+        assert_eq!(post(">>3 and >>1\n>>2").quotes(), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn quotes_without_duplicates() {
+        assert_eq!(post(">>5 >>5 >>6>>5").quotes(), vec![5, 6]);
+    }
+
+    #[test]
+    fn quotes_need_digits() {
+        assert!(post("> quoting >> nothing >>x").quotes().is_empty());
+    }
+
+    #[test]
+    fn quotes_are_capped() {
+        let message: Vec<String> = (1..=MAX_QUOTES + 5).map(|n| format!(">>{}", n)).collect();
+        let quotes = post(&message.join(" ")).quotes();
+        assert_eq!(quotes.len(), MAX_QUOTES);
+        assert_eq!(quotes[0], 1);
+    }
+
+    #[test]
+    fn board_channels_round_trip() {
+        assert_eq!(channel_board(&board_channel("b")), Some("b"));
+        assert_eq!(channel_board(&board_channel("board_x")), Some("board_x"));
+        assert_eq!(channel_board(BOARDS_CHANNEL), None);
+    }
+
+    fn viewer(identity: Option<&str>, staff: bool) -> Viewer {
+        Viewer {
+            identity: identity.map(str::to_owned),
+            staff,
+        }
+    }
+
+    #[test]
+    fn public_posts_are_visible_to_everyone() {
+        let post = post("hi");
+        assert!(post.visible_to(&viewer(None, false)));
+        assert!(post.visible_to(&viewer(Some("someone else"), false)));
+    }
+
+    #[test]
+    fn held_posts_are_visible_to_their_author_and_staff() {
+        let mut held = post("hi");
+        held.pending = true;
+        assert!(!held.visible_to(&viewer(None, false)));
+        assert!(!held.visible_to(&viewer(Some("someone else"), false)));
+        assert!(held.visible_to(&viewer(Some("someone"), false)));
+        assert!(held.visible_to(&viewer(None, true)));
+    }
+
+    #[test]
+    fn shadowed_posts_are_visible_to_their_author_and_staff() {
+        let mut shadowed = post("hi");
+        shadowed.shadow = true;
+        assert!(!shadowed.visible_to(&viewer(None, false)));
+        assert!(!shadowed.visible_to(&viewer(Some("someone else"), false)));
+        assert!(shadowed.visible_to(&viewer(Some("someone"), false)));
+        assert!(shadowed.visible_to(&viewer(Some("staff"), true)));
+    }
+
+    // needs a migrated database at DATABASE_URL, run with `cargo test -- --ignored`
+    #[actix_rt::test]
+    #[ignore]
+    async fn concurrent_replies_get_consecutive_numbers() {
+        dotenv::dotenv().ok();
+        let pool = crate::db::get_db_pool(&crate::CONFIG.db_url).await.unwrap();
+        let board = Board {
+            code: "numbering".to_owned(),
+            name: "Numbering".to_owned(),
+            ..Board::default()
+        };
+        // whatever a failed run left behind
+        Board::delete(&pool, &board.code).await.unwrap();
+        Board::post(&pool, &board).await.unwrap();
+
+        let opening = ThreadNew {
+            board: board.code.clone(),
+            title: "numbers".to_owned(),
+            name: "Anonymous".to_owned(),
+            message: "first".to_owned(),
+            image: None,
+            pending: false,
+            shadow: false,
+            r9k: false,
+        };
+        let thread = match Thread::post(&pool, opening, "op".to_owned()).await.unwrap() {
+            Posted::Done(thread) => thread,
+            Posted::Duplicate(_) => unreachable!(),
+        };
+        let replies = (0..20).map(|n| {
+            let pool = pool.clone();
+            let reply = PostNew {
+                thread: thread.id,
+                name: "Anonymous".to_owned(),
+                message: format!("reply {}", n),
+                identity: format!("replier {}", n),
+                image: None,
+                pending: false,
+                shadow: false,
+                r9k: false,
+            };
+            async move { Post::post(&pool, &reply).await }
+        });
+        let mut numbers: Vec<i64> = futures::future::join_all(replies)
+            .await
+            .into_iter()
+            .map(|posted| match posted.unwrap() {
+                Posted::Done(post) => post.number,
+                Posted::Duplicate(_) => unreachable!(),
+            })
+            .collect();
+        numbers.sort();
+        Board::delete(&pool, &board.code).await.unwrap();
+
+        // the opening post is 1
+        assert_eq!(numbers, (2..=21).collect::<Vec<i64>>());
+    }
+}
@@ -1,14 +1,68 @@
-use crate::util::multipart::MultipartError;
+use super::validation::{FieldError, Violation};
+use crate::db::model::Ban;
+use crate::util::{
+    multipart::MultipartError, pow::PowError, rate_limit::RateLimitError,
+    sse_thread::SubscribeError,
+};
 use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
-use serde_json::json;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use time::OffsetDateTime;
 
-#[derive(Debug)]
+// stable, machine-readable error kinds, sent as `code` in every error body
+// clients should branch on these instead of the message
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Internal,
+    BadRequest,
+    InvalidPayload,
+    ValidationFailed,
+    NotFound,
+    Unauthorized,
+    BoardNotFound,
+    BoardLocked,
+    ThreadNotFound,
+    ThreadLocked,
+    ThreadArchived,
+    RateLimited,
+    CaptchaRequired,
+    CaptchaInvalid,
+    PowRejected,
+    Banned,
+    PostRejected,
+    Muted,
+    DuplicateContent,
+    TooManySubscriptions,
+    OriginNotAllowed,
+    Unavailable,
+}
 
-// TODO: add more error types
+#[derive(Debug)]
 pub enum RequestError {
-    Teapot,
     NotFound,
     Unauthorized,
+    BoardNotFound,
+    BoardLocked,
+    ThreadNotFound,
+    ThreadLocked,
+    ThreadArchived,
+    RateLimited(Duration),
+    CaptchaRequired,
+    CaptchaInvalid,
+    PowRejected(PowError),
+    Banned(Ban),
+    PostRejected(String),
+    Muted(OffsetDateTime),
+    Duplicate {
+        field: &'static str,
+        muted_until: Option<OffsetDateTime>,
+    },
+    Subscribe(SubscribeError),
+    OriginNotAllowed,
+    InvalidPayload(serde_json::Error),
+    Validation(Vec<FieldError>),
     Internal(Box<dyn std::error::Error>),
     BadRequest(Box<dyn std::error::Error>),
 }
@@ -17,35 +71,153 @@ impl std::fmt::Display for RequestError {
         let message = match self {
             Self::Internal(_) => format!("Internal error"),
             Self::BadRequest(info) => format!("Bad request: {}", info),
+            Self::InvalidPayload(_) => "Invalid payload".to_owned(),
+            Self::Validation(errors) => match errors.first() {
+                Some(error) => error.to_string(),
+                None => "Validation failed".to_owned(),
+            },
             Self::NotFound => "Not found".to_owned(),
             Self::Unauthorized => "Unauthorized".to_owned(),
-            Self::Teapot => "Something fishy is going on".to_owned(),
+            Self::BoardNotFound => "Board doesn't exist".to_owned(),
+            Self::BoardLocked => "Board is read-only".to_owned(),
+            Self::ThreadNotFound => "Thread doesn't exist".to_owned(),
+            Self::ThreadLocked => "Thread is locked".to_owned(),
+            Self::ThreadArchived => "Thread is archived".to_owned(),
+            Self::RateLimited(wait) => format!(
+                "You're posting too fast, try again in {} seconds",
+                seconds(wait)
+            ),
+            Self::CaptchaRequired => "Solve the captcha to post".to_owned(),
+            Self::CaptchaInvalid => "Wrong or expired captcha".to_owned(),
+            Self::PowRejected(err) => format!("Proof of work rejected: {}", err),
+            Self::Banned(ban) if ban.reason.is_empty() => "You are banned".to_owned(),
+            Self::Banned(ban) => format!("You are banned: {}", ban.reason),
+            Self::PostRejected(reason) if reason.is_empty() => "Post rejected".to_owned(),
+            Self::PostRejected(reason) => format!("Post rejected: {}", reason),
+            Self::Muted(_) => "You are muted for posting duplicate content".to_owned(),
+            Self::Duplicate { field, .. } => format!("This {} was already posted here", field),
+            Self::Subscribe(err) => err.to_string(),
+            Self::OriginNotAllowed => "Origin not allowed".to_owned(),
         };
         write!(f, "{}", message)
     }
 }
 impl std::error::Error for RequestError {}
 
-impl ResponseError for RequestError {
-    fn error_response(&self) -> HttpResponse {
-        let response = json!({
+impl RequestError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Internal(_) => ErrorCode::Internal,
+            Self::BadRequest(_) => ErrorCode::BadRequest,
+            Self::InvalidPayload(_) => ErrorCode::InvalidPayload,
+            Self::Validation(_) => ErrorCode::ValidationFailed,
+            Self::NotFound => ErrorCode::NotFound,
+            Self::Unauthorized => ErrorCode::Unauthorized,
+            Self::BoardNotFound => ErrorCode::BoardNotFound,
+            Self::BoardLocked => ErrorCode::BoardLocked,
+            Self::ThreadNotFound => ErrorCode::ThreadNotFound,
+            Self::ThreadLocked => ErrorCode::ThreadLocked,
+            Self::ThreadArchived => ErrorCode::ThreadArchived,
+            Self::RateLimited(_) => ErrorCode::RateLimited,
+            Self::CaptchaRequired => ErrorCode::CaptchaRequired,
+            Self::CaptchaInvalid => ErrorCode::CaptchaInvalid,
+            Self::PowRejected(_) => ErrorCode::PowRejected,
+            Self::Banned(_) => ErrorCode::Banned,
+            Self::PostRejected(_) => ErrorCode::PostRejected,
+            Self::Muted(_) => ErrorCode::Muted,
+            Self::Duplicate { .. } => ErrorCode::DuplicateContent,
+            Self::Subscribe(SubscribeError::TooManyForAddress) => ErrorCode::TooManySubscriptions,
+            Self::Subscribe(_) => ErrorCode::Unavailable,
+            Self::OriginNotAllowed => ErrorCode::OriginNotAllowed,
+        }
+    }
+
+    // the form field the client should highlight, if there is one
+    fn field(&self) -> Option<String> {
+        match self {
+            Self::Validation(errors) => errors.first().map(|error| error.field.to_owned()),
+            Self::CaptchaRequired | Self::CaptchaInvalid => Some("captcha".to_owned()),
+            Self::PowRejected(_) => Some("pow".to_owned()),
+            Self::Duplicate { field, .. } => Some(field.to_string()),
+            // serde doesn't say which field was wrong, its message is in the details for people
+            _ => None,
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            Self::Validation(errors) => Some(json!(errors)),
+            Self::InvalidPayload(err) => Some(json!({
+                "reason": err.to_string(),
+                "line": err.line(),
+                "column": err.column()
+            })),
+            Self::RateLimited(wait) => Some(json!({ "retry_after": seconds(wait) })),
+            Self::Banned(ban) => Some(json!({
+                "reason": ban.reason,
+                "expires": ban.expires.map(|expires| expires.timestamp())
+            })),
+            Self::Muted(until) => Some(json!({ "muted_until": until.timestamp() })),
+            Self::Duplicate { muted_until, .. } => Some(json!({
+                "muted_until": muted_until.map(|until| until.timestamp())
+            })),
+            _ => None,
+        }
+    }
+
+    // the error body, websockets send it as a message of its own
+    pub fn body(&self) -> Value {
+        let mut body = json!({
             "success": false,
+            "code": self.code(),
             "message": self.to_string()
         });
+        if let Some(field) = self.field() {
+            body["field"] = field.into();
+        }
+        if let Some(details) = self.details() {
+            body["details"] = details;
+        }
+        body
+    }
+}
+
+impl ResponseError for RequestError {
+    fn error_response(&self) -> HttpResponse {
+        let response = self.body();
         let status = match self {
             Self::Internal(err) => {
                 eprintln!("Internal error: {}", err);
                 StatusCode::INTERNAL_SERVER_ERROR
             }
-            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
-            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::BadRequest(_) | Self::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::NotFound | Self::BoardNotFound | Self::ThreadNotFound => StatusCode::NOT_FOUND,
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
-            Self::Teapot => StatusCode::IM_A_TEAPOT,
+            Self::BoardLocked | Self::ThreadLocked => StatusCode::FORBIDDEN,
+            Self::CaptchaRequired | Self::CaptchaInvalid => StatusCode::FORBIDDEN,
+            Self::PowRejected(_) | Self::OriginNotAllowed => StatusCode::FORBIDDEN,
+            Self::Banned(_) | Self::PostRejected(_) | Self::Muted(_) => StatusCode::FORBIDDEN,
+            Self::Duplicate { .. } => StatusCode::CONFLICT,
+            Self::ThreadArchived => StatusCode::CONFLICT,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Subscribe(SubscribeError::TooManyForAddress) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Subscribe(_) => StatusCode::SERVICE_UNAVAILABLE,
         };
-        HttpResponse::build(status).json(response)
+        let mut builder = HttpResponse::build(status);
+        if let Self::RateLimited(wait) = self {
+            builder.header("Retry-After", seconds(wait).to_string());
+        }
+        builder.json(response)
     }
 }
 
+// whole seconds, rounded up so clients never retry too early
+fn seconds(duration: &Duration) -> u64 {
+    let secs = duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 };
+    secs.max(1)
+}
+
 impl From<sqlx::Error> for RequestError {
     fn from(error: sqlx::Error) -> Self {
         Self::Internal(error.into())
@@ -54,9 +226,42 @@ impl From<sqlx::Error> for RequestError {
 
 impl From<MultipartError> for RequestError {
     fn from(error: MultipartError) -> Self {
+        let file = |violation| {
+            Self::Validation(vec![FieldError {
+                field: "file",
+                violation,
+            }])
+        };
         match error {
+            MultipartError::InvalidField(err) => Self::InvalidPayload(err),
+            MultipartError::FileNotAllowed => file(Violation::NotAllowed),
+            MultipartError::UnsupportedType(allowed) => {
+                file(Violation::UnsupportedType { allowed })
+            }
+            MultipartError::FileTooLarge(max) => file(Violation::TooLarge { max }),
             MultipartError::Internal(_) => Self::Internal(error.into()),
             _ => Self::BadRequest(error.into()),
         }
     }
 }
+
+impl From<RateLimitError> for RequestError {
+    fn from(error: RateLimitError) -> Self {
+        match error {
+            RateLimitError::Limited(wait) => Self::RateLimited(wait),
+            RateLimitError::Internal(err) => Self::Internal(err.into()),
+        }
+    }
+}
+
+impl From<SubscribeError> for RequestError {
+    fn from(error: SubscribeError) -> Self {
+        Self::Subscribe(error)
+    }
+}
+
+impl From<PowError> for RequestError {
+    fn from(error: PowError) -> Self {
+        Self::PowRejected(error)
+    }
+}
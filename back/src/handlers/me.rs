@@ -0,0 +1,113 @@
+use super::{error::RequestError, event_stream, staff, types::MarkRead, Result};
+use crate::db::model::{Reply, Watch};
+use crate::util::{
+    client_ip,
+    sse_thread::{Broadcaster, Channel, Event},
+    GetIdentity,
+};
+use actix_identity::Identity;
+use actix_web::{
+    delete, get, post,
+    web::{Data, Json, Path},
+    HttpRequest, HttpResponse,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+// more than this and the client should mark some as read first
+const UNREAD_REPLIES: i64 = 100;
+const MAX_WATCHES: i64 = 200;
+
+// replies to your posts and updates to watched threads as they happen,
+// the same events websockets get on "me"
+#[get("/sse/me")]
+pub async fn me_subscribe(
+    brd: Data<Broadcaster>,
+    pool: Data<PgPool>,
+    identity: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    // a new identity has no posts yet, but it will once it posts from this page
+    let id = identity.get();
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let rx = brd.subscribe(
+        Channel::Me(id),
+        vec![Event::Ping.to_frame()],
+        viewer,
+        &client_ip(&req),
+    )?;
+    Ok(event_stream(rx))
+}
+
+#[get("/me/replies")]
+pub async fn my_replies(pool: Data<PgPool>, identity: Identity) -> Result<Json<Value>> {
+    let replies = match identity.identity() {
+        Some(identity) => Reply::fetch_unread(pool.as_ref(), &identity, UNREAD_REPLIES).await?,
+        None => Vec::new(),
+    };
+    Ok(Json(json!({
+        "success": true,
+        "replies": replies
+    })))
+}
+
+#[post("/me/replies/read")]
+pub async fn read_replies(
+    pool: Data<PgPool>,
+    identity: Identity,
+    info: Json<MarkRead>,
+) -> Result<Json<Value>> {
+    let marked = match identity.identity() {
+        Some(identity) => Reply::mark_read(pool.as_ref(), &identity, info.until).await?,
+        None => 0,
+    };
+    Ok(Json(json!({
+        "success": true,
+        "marked": marked
+    })))
+}
+
+#[get("/me/watch")]
+pub async fn my_watches(pool: Data<PgPool>, identity: Identity) -> Result<Json<Value>> {
+    let threads = match identity.identity() {
+        Some(identity) => Watch::fetch_all(pool.as_ref(), &identity).await?,
+        None => Vec::new(),
+    };
+    Ok(Json(json!({
+        "success": true,
+        "threads": threads
+    })))
+}
+
+// watching a thread again marks everything in it as seen
+#[post("/me/watch/{thread}")]
+pub async fn watch_thread(
+    pool: Data<PgPool>,
+    identity: Identity,
+    thread_id: Path<i32>,
+) -> Result<Json<Value>> {
+    let identity = identity.get();
+    if Watch::count(pool.as_ref(), &identity).await? >= MAX_WATCHES {
+        return Err(RequestError::BadRequest("Too many watched threads".into()));
+    }
+    if !Watch::post(pool.as_ref(), &identity, *thread_id).await? {
+        return Err(RequestError::ThreadNotFound);
+    }
+    Ok(Json(json!({ "success": true })))
+}
+
+#[delete("/me/watch/{thread}")]
+pub async fn unwatch_thread(
+    pool: Data<PgPool>,
+    identity: Identity,
+    thread_id: Path<i32>,
+) -> Result<Json<Value>> {
+    let watching = match identity.identity() {
+        Some(identity) => Watch::delete(pool.as_ref(), &identity, *thread_id).await?,
+        None => false,
+    };
+    if !watching {
+        return Err(RequestError::NotFound);
+    }
+    Ok(Json(json!({ "success": true })))
+}
@@ -1,26 +1,51 @@
 mod error;
+mod me;
+mod preconditions;
+mod staff;
 mod types;
+mod validation;
+mod ws;
 
-use crate::db::model::{Board, ImageNew, Post, PostNew, Thread, ThreadNew, ThreadWithPosts};
-use crate::util::multipart;
+use crate::db::model::{
+    Board, BoardPage, Captcha, CatalogThread, EventKind, ImageNew, Post, PostNew, Posted,
+    SearchCursor, SearchFilter, SearchResult, Thread, ThreadNew, ThreadWithPosts,
+};
+use crate::util::multipart::{self, SavedFile};
 use crate::util::{
-    sse_thread::{Broadcaster, Event},
+    captcha, client_ip,
+    filters::{Fields, RuleCache},
+    notify,
+    pow::{PowGuard, Proof},
+    rate_limit::{Action, RateLimiter},
+    sse_thread::{Broadcaster, Channel, Client, Event},
     GetIdentity,
 };
 use actix_identity::Identity;
 use actix_multipart::Multipart;
 use actix_web::{
     get, post,
-    web::{Data, Json, Path},
-    HttpResponse,
+    web::{block, Data, Json, Path, Query},
+    HttpRequest, HttpResponse,
 };
 use error::RequestError;
 use serde_json::{json, Value};
-use tokio::sync::Mutex;
 use types::*;
+use validation::Validate;
+
+pub use me::{me_subscribe, my_replies, my_watches, read_replies, unwatch_thread, watch_thread};
+pub use staff::{
+    approve_post, ban_author, board_rules, delete_ban, delete_board, delete_board_rule,
+    delete_post, edit_board, edit_post, lock_thread, move_thread, new_board, new_board_rule,
+    pending_posts, reject_post, remove_image, sticky_thread, unlock_thread, unsticky_thread,
+};
+pub use ws::websocket;
 
 type Result<T> = std::result::Result<T, RequestError>;
 
+const THREADS_PER_PAGE: i64 = 10;
+const PREVIEW_REPLIES: i64 = 5;
+const SEARCH_RESULTS: i64 = 25;
+
 #[get("/boards")]
 pub async fn boards(pool: Data<sqlx::PgPool>) -> Result<Json<Vec<Board>>> {
     let boards = Board::fetch_all(pool.as_ref()).await?;
@@ -28,45 +53,137 @@ pub async fn boards(pool: Data<sqlx::PgPool>) -> Result<Json<Vec<Board>>> {
 }
 
 #[get("/boards/{board}/catalog")]
-pub async fn catalog(pool: Data<sqlx::PgPool>, path: Path<String>) -> Result<Json<Vec<Thread>>> {
-    let threads = Thread::fetch_catalog(pool.as_ref(), &path.into_inner()).await?;
+pub async fn catalog(
+    pool: Data<sqlx::PgPool>,
+    path: Path<String>,
+    query: Query<CatalogQuery>,
+    identity: Identity,
+) -> Result<Json<Vec<CatalogThread>>> {
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let threads =
+        CatalogThread::fetch(pool.as_ref(), &path.into_inner(), query.sort, &viewer).await?;
     Ok(Json(threads))
 }
 
+#[get("/boards/{board}/page/{page}")]
+pub async fn board_page(
+    pool: Data<sqlx::PgPool>,
+    path: Path<(String, i64)>,
+    identity: Identity,
+) -> Result<Json<BoardPage>> {
+    let (board, page) = path.into_inner();
+    if page < 0 {
+        return Err(RequestError::NotFound);
+    }
+    let board = Board::fetch(pool.as_ref(), &board)
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let page = BoardPage::fetch(
+        pool.as_ref(),
+        &board.code,
+        page,
+        THREADS_PER_PAGE,
+        PREVIEW_REPLIES,
+        &viewer,
+    )
+    .await?;
+    Ok(Json(page))
+}
+
+// resolves >>links, clients take the thread from here and scroll to the post
+#[get("/boards/{board}/post/{number}")]
+pub async fn board_post(
+    pool: Data<sqlx::PgPool>,
+    path: Path<(String, i64)>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    let (board, number) = path.into_inner();
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let post = Post::fetch_by_number(pool.as_ref(), &board, number, &viewer)
+        .await?
+        .ok_or(RequestError::NotFound)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "thread": post.thread,
+        "post": post
+    })))
+}
+
+// `next` is the cursor for the following page, missing on the last one
+#[get("/search")]
+pub async fn search(
+    pool: Data<sqlx::PgPool>,
+    query: Query<SearchQuery>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    let query = query.into_inner();
+    validation::validate_search(&query)?;
+    let cursor = query.cursor.as_deref().and_then(SearchCursor::parse);
+    let filter = SearchFilter {
+        query: query.q,
+        board: query.board,
+        thread: query.thread,
+        has_image: query.has_image,
+        before: query.before,
+        after: query.after,
+        archived: query.archived,
+    };
+
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let results = SearchResult::search(
+        pool.as_ref(),
+        &filter,
+        cursor.as_ref(),
+        SEARCH_RESULTS,
+        &viewer,
+    )
+    .await?;
+    let next = match results.last() {
+        Some(last) if results.len() as i64 == SEARCH_RESULTS => Some(last.cursor().to_string()),
+        _ => None,
+    };
+
+    Ok(Json(json!({
+        "success": true,
+        "results": results,
+        "next": next
+    })))
+}
+
 #[post("/boards/{board}")]
 pub async fn new_thread(
     pool: Data<sqlx::PgPool>,
+    limiter: Data<RateLimiter>,
+    pow_guard: Data<PowGuard>,
+    rules: Data<RuleCache>,
     path: Path<String>,
     identity: Identity,
+    req: HttpRequest,
     mp: Multipart,
 ) -> Result<Json<Value>> {
     let identity = identity.get();
-    let (info, mut images) = multipart::to_payload::<NewThread>(mp).await?;
-
-    if images.len() == 0 {
-        return Err(RequestError::BadRequest(
-            "Threads should have an image".into(),
-        ));
-    }
-
-    let new_thread = ThreadNew {
-        board: path.into_inner(),
-        title: info.title.unwrap_or_default().chars().take(100).collect(),
-        name: info.name.unwrap_or_default().chars().take(50).collect(),
-        message: info.message.chars().take(5000).collect(),
-        image: if images.len() > 0 {
-            let i = images.remove(0);
-            Some(ImageNew {
-                name: i.name,
-                path: i.path.clone(),
-                preview_path: i.path,
-            })
-        } else {
-            None
-        },
+    let code = path.into_inner();
+    // a bad proof of work is turned away before it costs a single query
+    let proof = pow_guard.verify_request(&req, &identity, Some(&code))?;
+    preconditions::check_proof(pool.as_ref(), proof.as_ref()).await?;
+    // a missing or locked board shouldn't cost us the upload
+    let board = preconditions::thread_target(pool.as_ref(), &code).await?;
+    // and neither should a flood of them
+    limiter
+        .check(Action::Thread, &board, &[&identity, &client_ip(&req)])
+        .await?;
+    let (info, images) = multipart::to_payload::<NewThread>(mp, &board).await?;
+    let posting = Posting {
+        pool,
+        limiter,
+        pow_guard,
+        rules,
     };
-
-    let thread = Thread::post(pool.as_ref(), new_thread, identity).await?;
+    let poster = Poster { identity, proof };
+    let posted = posting.thread(&board, poster, info, &images).await;
+    let thread = keep_uploads(posted, images).await?;
 
     Ok(Json(json!({
         "success": true,
@@ -77,55 +194,366 @@ pub async fn new_thread(
 #[post("/thread/{thread}")]
 pub async fn new_post(
     pool: Data<sqlx::PgPool>,
-    brd: Data<Mutex<Broadcaster>>,
+    limiter: Data<RateLimiter>,
+    pow_guard: Data<PowGuard>,
+    rules: Data<RuleCache>,
     path: Path<i32>,
     identity: Identity,
+    req: HttpRequest,
     mp: Multipart,
 ) -> Result<Json<Value>> {
     let identity = identity.get();
-    let (info, mut images) = multipart::to_payload::<NewPost>(mp).await?;
-
-    let new_post = PostNew {
-        identity: identity,
-        name: info.name.unwrap_or_default().chars().take(50).collect(),
-        message: info.message.chars().take(5000).collect(),
-        thread: path.into_inner(),
-        image: if images.len() > 0 {
-            let i = images.remove(0);
-            Some(ImageNew {
-                name: i.name,
-                path: i.path.clone(),
-                preview_path: i.path,
-            })
-        } else {
-            None
-        },
+    // the board is only known once the thread is, it's checked along with the captcha
+    let proof = pow_guard.verify_request(&req, &identity, None)?;
+    preconditions::check_proof(pool.as_ref(), proof.as_ref()).await?;
+    // the same goes for missing, archived and locked threads
+    let (board, thread) = preconditions::reply_target(pool.as_ref(), path.into_inner()).await?;
+    let (info, images) = multipart::to_payload::<NewPost>(mp, &board).await?;
+    let posting = Posting {
+        pool,
+        limiter,
+        pow_guard,
+        rules,
     };
+    let poster = Poster { identity, proof };
+    let posted = posting
+        .reply_to(&board, &thread, poster, &client_ip(&req), info, &images)
+        .await;
+    let post = keep_uploads(posted, images).await?;
+
+    Ok(Json(json!({
+        "success": true,
+        "post": post
+    })))
+}
+
+// nothing points at the upload of a post that didn't go in
+async fn keep_uploads<T>(posted: Result<T>, images: Vec<SavedFile>) -> Result<T> {
+    if posted.is_err() {
+        multipart::discard(images).await;
+    }
+    posted
+}
+
+fn image_new(file: &SavedFile) -> ImageNew {
+    ImageNew {
+        name: file.name.clone(),
+        path: file.path.clone(),
+        preview_path: file.path.clone(),
+        hash: file.hash.clone(),
+        width: file.dimensions.map(|(width, _)| width as i32),
+        height: file.dimensions.map(|(_, height)| height as i32),
+    }
+}
+
+// who's posting, with the proof of work they solved if any
+struct Poster {
+    identity: String,
+    proof: Option<Proof>,
+}
+
+// what it takes to post, shared by the forms above and websockets
+#[derive(Clone)]
+struct Posting {
+    pool: Data<sqlx::PgPool>,
+    limiter: Data<RateLimiter>,
+    pow_guard: Data<PowGuard>,
+    rules: Data<RuleCache>,
+}
+
+impl Posting {
+    // rate limited by the handler, before the upload
+    async fn thread(
+        &self,
+        board: &Board,
+        poster: Poster,
+        mut info: NewThread,
+        images: &[SavedFile],
+    ) -> Result<ThreadWithPosts> {
+        let Poster { identity, proof } = poster;
+        let pool = self.pool.as_ref();
+        info.validate(images)?;
+
+        preconditions::check_files(pool, board, None, images).await?;
+        let shadow = preconditions::check_ban(pool, board, &identity).await?;
+        let captcha = preconditions::check_captcha(
+            pool,
+            board,
+            &identity,
+            true,
+            info.captcha.as_deref(),
+            proof.as_ref(),
+        )
+        .await?;
+
+        let mut title = info.title.take().unwrap_or_default();
+        let mut name = info.name.take().unwrap_or_default();
+        let held = preconditions::apply_rules(
+            pool,
+            self.rules.as_ref(),
+            board,
+            &identity,
+            Fields {
+                message: &mut info.message,
+                name: &mut name,
+                title: Some(&mut title),
+            },
+        )
+        .await?;
+        validation::validate_rewritten(Some(&title), &name, &info.message, false)?;
+        if name.trim().is_empty() {
+            name = board.default_name.clone();
+        }
+        let pending = held || preconditions::needs_premod(pool, board, &identity).await?;
+        preconditions::check_r9k(pool, board, &identity).await?;
+        if captcha {
+            preconditions::take_captcha(pool, &identity, info.captcha.as_deref()).await?;
+        }
+        preconditions::take_proof(pool, proof.as_ref()).await?;
 
-    let post = Post::post(pool.as_ref(), &new_post).await?;
+        let new_thread = ThreadNew {
+            board: board.code.clone(),
+            title,
+            name,
+            message: info.message,
+            image: images.first().map(image_new),
+            pending,
+            shadow,
+            r9k: board.r9k,
+        };
 
-    brd.lock().await.send(post.thread, Event::Post(&post));
+        let thread = match Thread::post(pool, new_thread, identity.clone()).await? {
+            Posted::Done(thread) => thread,
+            Posted::Duplicate(kind) => {
+                return Err(preconditions::duplicate(pool, board, &identity, kind).await)
+            }
+        };
+        self.pow_guard.record_post(&board.code);
+
+        notify::update_locks(pool, &board.code).await;
+        // board streams show it as a new thread
+        notify::publish(
+            pool,
+            &board.code,
+            thread.id,
+            EventKind::Post,
+            thread.last_post_id(),
+        )
+        .await;
+        Ok(thread)
+    }
+
+    // text only, so there's no upload to hold off on
+    async fn reply(&self, thread_id: i32, poster: Poster, ip: &str, info: NewPost) -> Result<Post> {
+        preconditions::check_proof(self.pool.as_ref(), poster.proof.as_ref()).await?;
+        let (board, thread) = preconditions::reply_target(self.pool.as_ref(), thread_id).await?;
+        self.reply_to(&board, &thread, poster, ip, info, &[]).await
+    }
+
+    async fn reply_to(
+        &self,
+        board: &Board,
+        thread: &Thread,
+        poster: Poster,
+        ip: &str,
+        mut info: NewPost,
+        images: &[SavedFile],
+    ) -> Result<Post> {
+        let Poster { identity, proof } = poster;
+        let pool = self.pool.as_ref();
+        info.validate(images)?;
+
+        preconditions::check_files(pool, board, Some(thread), images).await?;
+        let shadow = preconditions::check_ban(pool, board, &identity).await?;
+        let captcha = preconditions::check_captcha(
+            pool,
+            board,
+            &identity,
+            false,
+            info.captcha.as_deref(),
+            proof.as_ref(),
+        )
+        .await?;
+
+        // whether it's an image reply is only known once the file is in,
+        // keep_uploads gets rid of it if this fails
+        let action = if !images.is_empty() {
+            Action::ImageReply
+        } else {
+            Action::Reply
+        };
+        self.limiter.check(action, board, &[&identity, ip]).await?;
+
+        let mut name = info.name.take().unwrap_or_default();
+        let held = preconditions::apply_rules(
+            pool,
+            self.rules.as_ref(),
+            board,
+            &identity,
+            Fields {
+                message: &mut info.message,
+                name: &mut name,
+                title: None,
+            },
+        )
+        .await?;
+        validation::validate_rewritten(None, &name, &info.message, !images.is_empty())?;
+        if name.trim().is_empty() {
+            name = board.default_name.clone();
+        }
+        let pending = held || preconditions::needs_premod(pool, board, &identity).await?;
+        preconditions::check_r9k(pool, board, &identity).await?;
+        if captcha {
+            preconditions::take_captcha(pool, &identity, info.captcha.as_deref()).await?;
+        }
+        preconditions::take_proof(pool, proof.as_ref()).await?;
+
+        let new_post = PostNew {
+            identity: identity,
+            name,
+            message: info.message,
+            thread: thread.id,
+            image: images.first().map(image_new),
+            pending,
+            shadow,
+            r9k: board.r9k,
+        };
+
+        let post = match Post::post(pool, &new_post).await? {
+            Posted::Done(post) => post,
+            Posted::Duplicate(kind) => {
+                return Err(preconditions::duplicate(pool, board, &new_post.identity, kind).await)
+            }
+        };
+        self.pow_guard.record_post(&board.code);
+
+        // held and shadowed posts only go out to their author and staff
+        notify::publish(
+            pool,
+            &post.board,
+            post.thread,
+            EventKind::Post,
+            Some(post.id),
+        )
+        .await;
+        Ok(post)
+    }
+}
+
+#[get("/captcha")]
+pub async fn new_captcha(pool: Data<sqlx::PgPool>, identity: Identity) -> Result<HttpResponse> {
+    let identity = identity.get();
+    let challenge = block(captcha::generate)
+        .await
+        .map_err(|err| RequestError::Internal(err.to_string().into()))?;
+
+    Captcha::store(
+        pool.as_ref(),
+        &identity,
+        &captcha::hash_answer(&challenge.answer),
+        crate::CONFIG.captcha_ttl,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .header("cache-control", "no-store")
+        .body(challenge.png))
+}
+
+#[get("/boards/{board}/challenge")]
+pub async fn pow_challenge(
+    pool: Data<sqlx::PgPool>,
+    pow_guard: Data<PowGuard>,
+    path: Path<String>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    let identity = identity.get();
+    let board = Board::fetch(pool.as_ref(), &path.into_inner())
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    if board.pow_difficulty <= 0 {
+        return Err(RequestError::NotFound);
+    }
+
+    let challenge = pow_guard.issue(&board.code, &identity, board.pow_difficulty as u32);
 
     Ok(Json(json!({
         "success": true,
-        "post": post
+        "challenge": challenge
     })))
 }
 
-#[get("/sse/thread/{thread}")]
-async fn thread_subscribe(
-    brd: Data<Mutex<Broadcaster>>,
+// how many people have the thread open on this instance, staff only
+#[get("/sse/thread/{thread}/subscribers")]
+pub async fn thread_subscribers(
+    brd: Data<Broadcaster>,
+    pool: Data<sqlx::PgPool>,
     path: Path<i32>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    staff::require_staff(pool.as_ref(), &identity).await?;
+    Ok(Json(json!({
+        "success": true,
+        "subscribers": brd.subscribers(&Channel::Thread(path.into_inner()))
+    })))
+}
+
+// live update health on this instance, lagging clients are the ones about to be dropped
+#[get("/sse/stats")]
+pub async fn stream_stats(
+    brd: Data<Broadcaster>,
+    pool: Data<sqlx::PgPool>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    staff::require_staff(pool.as_ref(), &identity).await?;
+    Ok(Json(json!({
+        "success": true,
+        "stats": brd.stats()
+    })))
+}
+
+// compact updates for every thread on the board, enough to keep a catalog current
+#[get("/sse/boards/{board}")]
+pub async fn board_subscribe(
+    brd: Data<Broadcaster>,
+    path: Path<String>,
     pool: Data<sqlx::PgPool>,
+    identity: Identity,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
-    let thread = ThreadWithPosts::fetch(pool.as_ref(), path.into_inner()).await?;
+    let board = Board::fetch(pool.as_ref(), &path.into_inner())
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let rx = brd.subscribe(
+        Channel::Board(board.code),
+        vec![Event::Ping.to_frame()],
+        viewer,
+        &client_ip(&req),
+    )?;
+    Ok(event_stream(rx))
+}
 
-    let rx = brd
-        .lock()
-        .await
-        .new_subscriber(thread)
-        .ok_or(RequestError::Teapot)?;
+// the same for all boards at once
+#[get("/sse/overboard")]
+pub async fn overboard_subscribe(
+    brd: Data<Broadcaster>,
+    pool: Data<sqlx::PgPool>,
+    identity: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let rx = brd.subscribe(
+        Channel::Overboard,
+        vec![Event::Ping.to_frame()],
+        viewer,
+        &client_ip(&req),
+    )?;
+    Ok(event_stream(rx))
+}
 
+fn event_stream(rx: Client) -> HttpResponse {
     let mut res = HttpResponse::Ok()
         .header("content-type", "text/event-stream")
         .no_chunking(0)
@@ -137,5 +565,50 @@ async fn thread_subscribe(
     res.headers_mut()
         .remove(actix_web::http::header::CONTENT_LENGTH);
 
-    Ok(res)
+    res
+}
+
+#[get("/sse/thread/{thread}")]
+async fn thread_subscribe(
+    brd: Data<Broadcaster>,
+    path: Path<i32>,
+    pool: Data<sqlx::PgPool>,
+    identity: Identity,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let thread_id = path.into_inner();
+    let viewer = staff::viewer(pool.as_ref(), &identity).await?;
+    let last_event_id = req
+        .headers()
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse::<i64>().ok());
+    let address = client_ip(&req);
+
+    // joined before anything is read, posts sent in the meantime wait and the ones that
+    // are in the snapshot or replay as well only go out once
+    let (joined, missed) = brd.join_thread(thread_id, last_event_id, &viewer, &address)?;
+    let missed = match (last_event_id, missed) {
+        (_, Some(missed)) => Some(missed),
+        // browsers resend the id of the last event they got when they reconnect
+        (Some(last_event_id), None) => {
+            Thread::fetch(pool.as_ref(), thread_id)
+                .await?
+                .ok_or(RequestError::ThreadNotFound)?;
+            Post::fetch_published_after(pool.as_ref(), thread_id, last_event_id, &viewer).await?
+        }
+        (None, None) => None,
+    };
+    let rx = match missed {
+        Some(missed) => joined.resume(missed, viewer),
+        // a first visit, or the client was gone for too long to tell what it missed
+        None => {
+            let thread = ThreadWithPosts::fetch(pool.as_ref(), thread_id, &viewer)
+                .await?
+                .ok_or(RequestError::ThreadNotFound)?;
+            joined.snapshot(&thread, viewer)
+        }
+    };
+
+    Ok(event_stream(rx))
 }
@@ -0,0 +1,238 @@
+use super::{
+    error::RequestError,
+    validation::{Violation, Violations},
+    Result,
+};
+use crate::db::model::{
+    Ban, BanNew, Board, Captcha, CaptchaMode, Post, R9k, R9kKind, SpentChallenge, Thread,
+};
+use crate::util::{
+    captcha,
+    filters::{self, Fields, RuleCache, Verdict},
+    multipart::SavedFile,
+    pow::{PowError, Proof},
+};
+use sqlx::PgPool;
+use time::{Duration, OffsetDateTime};
+
+// every check a post has to pass before we let it anywhere near Post::post
+
+fn check_board(board: &Board) -> Result<()> {
+    if board.locked {
+        return Err(RequestError::BoardLocked);
+    }
+    Ok(())
+}
+
+pub async fn thread_target(pool: &PgPool, board: &str) -> Result<Board> {
+    let board = Board::fetch(pool, board)
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    check_board(&board)?;
+    Ok(board)
+}
+
+pub async fn reply_target(pool: &PgPool, thread_id: i32) -> Result<(Board, Thread)> {
+    let thread = Thread::fetch(pool, thread_id)
+        .await?
+        .ok_or(RequestError::ThreadNotFound)?;
+    if !thread.open {
+        return Err(RequestError::ThreadArchived);
+    }
+    if thread.locked {
+        return Err(RequestError::ThreadLocked);
+    }
+    let board = Board::fetch(pool, &thread.board)
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    check_board(&board)?;
+    Ok((board, thread))
+}
+
+// the type and size were checked while the file came in, see multipart::to_payload
+// `thread` is None for new threads
+pub async fn check_files(
+    pool: &PgPool,
+    board: &Board,
+    thread: Option<&Thread>,
+    files: &[SavedFile],
+) -> Result<()> {
+    let mut violations = Violations::default();
+    match files.first() {
+        None if thread.is_none() && board.op_image_required && !board.text_only => {
+            violations.push("file", Violation::Empty)
+        }
+        None => {}
+        Some(_) => {
+            if let Some(thread) = thread {
+                if Thread::image_count(pool, thread.id).await? >= board.image_limit as i64 {
+                    violations.push(
+                        "file",
+                        Violation::LimitReached {
+                            max: board.image_limit as usize,
+                        },
+                    );
+                }
+            }
+        }
+    }
+    violations.finish()
+}
+
+// a proof of work for this board can stand in for the captcha, if the board accepts them
+// returns whether the answer still has to be checked, see take_captcha
+pub async fn check_captcha(
+    pool: &PgPool,
+    board: &Board,
+    identity: &str,
+    new_thread: bool,
+    answer: Option<&str>,
+    proof: Option<&Proof>,
+) -> Result<bool> {
+    let required = match board.captcha_mode() {
+        CaptchaMode::Never => false,
+        CaptchaMode::Always => true,
+        CaptchaMode::Threads => new_thread,
+        CaptchaMode::NewIdentities(minutes) => {
+            match Post::identity_first_seen(pool, identity).await? {
+                Some(first) => OffsetDateTime::now_utc() - first < Duration::minutes(minutes),
+                None => true,
+            }
+        }
+    };
+    if !required {
+        return Ok(false);
+    }
+    if let Some(proof) = proof {
+        if proof.board != board.code {
+            return Err(PowError::WrongBoard.into());
+        }
+        if board.pow_difficulty > 0 && proof.difficulty >= board.pow_difficulty as u32 {
+            return Ok(false);
+        }
+    }
+    answer.ok_or(RequestError::CaptchaRequired)?;
+    Ok(true)
+}
+
+// a replayed challenge is turned away before the upload, it's only spent by take_proof
+pub async fn check_proof(pool: &PgPool, proof: Option<&Proof>) -> Result<()> {
+    match proof {
+        Some(proof) if SpentChallenge::exists(pool, &proof.challenge).await? => {
+            Err(PowError::Reused.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// like the captcha, spent once nothing else can turn the post away
+pub async fn take_proof(pool: &PgPool, proof: Option<&Proof>) -> Result<()> {
+    match proof {
+        Some(proof) if !SpentChallenge::spend(pool, &proof.challenge, proof.expires).await? => {
+            Err(PowError::Reused.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// the captcha is used up by checking it, so this goes last, a post turned away
+// for anything else doesn't cost the poster their captcha
+pub async fn take_captcha(pool: &PgPool, identity: &str, answer: Option<&str>) -> Result<()> {
+    let answer = answer.ok_or(RequestError::CaptchaRequired)?;
+    match Captcha::take(pool, identity).await? {
+        Some(hash) if hash == captcha::hash_answer(answer) => Ok(()),
+        _ => Err(RequestError::CaptchaInvalid),
+    }
+}
+
+// shadow bans don't stop anyone from posting, returns whether the post should be shadowed instead
+pub async fn check_ban(pool: &PgPool, board: &Board, identity: &str) -> Result<bool> {
+    match Ban::fetch_active(pool, identity, &board.code).await? {
+        Some(ban) if ban.shadow => Ok(true),
+        Some(ban) => Err(RequestError::Banned(ban)),
+        None => Ok(false),
+    }
+}
+
+// runs the board's rules over the post, returns whether the post should be held for moderation
+pub async fn apply_rules(
+    pool: &PgPool,
+    rules: &RuleCache,
+    board: &Board,
+    identity: &str,
+    fields: Fields<'_>,
+) -> Result<bool> {
+    let rules = rules.rules(pool, &board.code).await?;
+    match filters::apply(&rules, fields) {
+        Verdict::Accept => Ok(false),
+        Verdict::Hold => Ok(true),
+        Verdict::Reject(reason) => Err(RequestError::PostRejected(reason)),
+        Verdict::Ban { reason, minutes } => {
+            let ban = Ban::post(
+                pool,
+                &BanNew {
+                    identity: identity.to_owned(),
+                    board: Some(board.code.clone()),
+                    reason,
+                    minutes,
+                    shadow: false,
+                },
+            )
+            .await?;
+            Err(RequestError::Banned(ban))
+        }
+    }
+}
+
+// brand-new identities on boards with pre-moderation have to wait for a moderator
+pub async fn needs_premod(pool: &PgPool, board: &Board, identity: &str) -> Result<bool> {
+    if let Some(minutes) = board.premod_age {
+        let young = match Post::identity_first_approved(pool, identity).await? {
+            Some(first) => OffsetDateTime::now_utc() - first < Duration::minutes(minutes as i64),
+            None => true,
+        };
+        if young {
+            return Ok(true);
+        }
+    }
+    if let Some(posts) = board.premod_posts {
+        if Post::approved_count(pool, identity).await? < posts as i64 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// boards in r9k mode only take messages and images nobody posted there before,
+// Post::post is what finds out, this only keeps muted posters out
+pub async fn check_r9k(pool: &PgPool, board: &Board, identity: &str) -> Result<()> {
+    if !board.r9k {
+        return Ok(());
+    }
+    if let Some(until) = R9k::muted_until(pool, &board.code, identity).await? {
+        return Err(RequestError::Muted(until));
+    }
+    Ok(())
+}
+
+// for posts Post::post turned down, repeat offenders get muted
+pub async fn duplicate(
+    pool: &PgPool,
+    board: &Board,
+    identity: &str,
+    kind: R9kKind,
+) -> RequestError {
+    let muted_until = match board.r9k_mute_seconds {
+        Some(seconds) if seconds > 0 => {
+            match R9k::mute(pool, &board.code, identity, seconds).await {
+                Ok(until) => Some(until),
+                Err(err) => return err.into(),
+            }
+        }
+        _ => None,
+    };
+    RequestError::Duplicate {
+        field: kind.name(),
+        muted_until,
+    }
+}
@@ -0,0 +1,546 @@
+use super::{
+    error::RequestError,
+    types::{EditPost, MoveThread, NewBan, NewRule},
+    validation::{self, FieldError, Violation},
+    Result,
+};
+use crate::db::model::{
+    Ban, BanNew, Board, BoardRule, BoardRuleNew, EventKind, Post, Staff, Thread, Viewer,
+};
+use crate::util::filters::{CompiledRule, RuleCache};
+use crate::util::{multipart, notify};
+use actix_identity::Identity;
+use actix_web::{
+    delete, get, post, put,
+    web::{Data, Json, Path},
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+// unlike GetIdentity::get this never creates a new identity, a fresh one can't be staff anyway
+pub async fn require_staff(pool: &PgPool, identity: &Identity) -> Result<()> {
+    match identity.identity() {
+        Some(id) if Staff::is_staff(pool, &id).await? => Ok(()),
+        _ => Err(RequestError::Unauthorized),
+    }
+}
+
+pub async fn require_admin(pool: &PgPool, identity: &Identity) -> Result<()> {
+    match identity.identity() {
+        Some(id) if Staff::is_admin(pool, &id).await? => Ok(()),
+        _ => Err(RequestError::Unauthorized),
+    }
+}
+
+pub async fn viewer(pool: &PgPool, identity: &Identity) -> Result<Viewer> {
+    let identity = identity.identity();
+    let staff = match &identity {
+        Some(id) => Staff::is_staff(pool, id).await?,
+        None => false,
+    };
+    Ok(Viewer { identity, staff })
+}
+
+fn thread_response(thread: Option<Thread>) -> Result<Json<Value>> {
+    let thread = thread.ok_or(RequestError::ThreadNotFound)?;
+    Ok(Json(json!({
+        "success": true,
+        "thread": thread
+    })))
+}
+
+#[post("/thread/{thread}/sticky")]
+pub async fn sticky_thread(
+    pool: Data<PgPool>,
+    path: Path<i32>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let thread = Thread::set_sticky(pool.as_ref(), path.into_inner(), true).await?;
+    if let Some(thread) = &thread {
+        notify::publish(
+            pool.as_ref(),
+            &thread.board,
+            thread.id,
+            EventKind::Sticky,
+            None,
+        )
+        .await;
+    }
+    thread_response(thread)
+}
+
+#[delete("/thread/{thread}/sticky")]
+pub async fn unsticky_thread(
+    pool: Data<PgPool>,
+    path: Path<i32>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let thread = Thread::set_sticky(pool.as_ref(), path.into_inner(), false).await?;
+    if let Some(thread) = &thread {
+        notify::publish(
+            pool.as_ref(),
+            &thread.board,
+            thread.id,
+            EventKind::Sticky,
+            None,
+        )
+        .await;
+    }
+    thread_response(thread)
+}
+
+#[post("/thread/{thread}/lock")]
+pub async fn lock_thread(
+    pool: Data<PgPool>,
+    path: Path<i32>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let thread = Thread::set_locked(pool.as_ref(), path.into_inner(), true).await?;
+    if let Some(thread) = &thread {
+        notify::publish(
+            pool.as_ref(),
+            &thread.board,
+            thread.id,
+            EventKind::Lock,
+            None,
+        )
+        .await;
+    }
+    thread_response(thread)
+}
+
+#[delete("/thread/{thread}/lock")]
+pub async fn unlock_thread(
+    pool: Data<PgPool>,
+    path: Path<i32>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let thread = Thread::set_locked(pool.as_ref(), path.into_inner(), false).await?;
+    if let Some(thread) = &thread {
+        notify::publish(
+            pool.as_ref(),
+            &thread.board,
+            thread.id,
+            EventKind::Lock,
+            None,
+        )
+        .await;
+    }
+    thread_response(thread)
+}
+
+// the old board's streams see it leave, the new board's see a new thread
+#[post("/thread/{thread}/move")]
+pub async fn move_thread(
+    pool: Data<PgPool>,
+    path: Path<i32>,
+    identity: Identity,
+    info: Json<MoveThread>,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let thread_id = path.into_inner();
+    let from = Thread::fetch(pool.as_ref(), thread_id)
+        .await?
+        .ok_or(RequestError::ThreadNotFound)?;
+    let board = Board::fetch(pool.as_ref(), &info.board)
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    if board.code == from.board {
+        return thread_response(Some(from));
+    }
+    if board.locked {
+        return Err(RequestError::BoardLocked);
+    }
+    if board.text_only && Thread::has_images(pool.as_ref(), thread_id).await? {
+        return Err(RequestError::Validation(vec![FieldError {
+            field: "board",
+            violation: Violation::TextOnly,
+        }]));
+    }
+
+    let thread = Thread::move_to(pool.as_ref(), thread_id, &board.code)
+        .await?
+        .ok_or(RequestError::ThreadNotFound)?;
+    notify::publish(pool.as_ref(), &from.board, thread.id, EventKind::Move, None).await;
+    notify::update_locks(pool.as_ref(), &board.code).await;
+    // posts are numbered per board, so they all got new numbers
+    Ok(Json(json!({
+        "success": true,
+        "thread": thread,
+        "warning": "Posts were renumbered, links to the thread's old board and post numbers no longer work"
+    })))
+}
+
+fn board_response(board: Board) -> Result<Json<Value>> {
+    Ok(Json(json!({
+        "success": true,
+        "board": board
+    })))
+}
+
+#[post("/boards")]
+pub async fn new_board(
+    pool: Data<PgPool>,
+    identity: Identity,
+    info: Json<Board>,
+) -> Result<Json<Value>> {
+    require_admin(pool.as_ref(), &identity).await?;
+    let board = info.into_inner();
+    validation::validate_board(&board)?;
+    if Board::fetch(pool.as_ref(), &board.code).await?.is_some() {
+        return Err(RequestError::Validation(vec![FieldError {
+            field: "code",
+            violation: Violation::Taken,
+        }]));
+    }
+
+    let board = Board::post(pool.as_ref(), &board).await?;
+    notify::announce_board(pool.as_ref(), &board.code).await;
+    board_response(board)
+}
+
+// only the settings present in the body change, everything else stays as it was
+#[put("/boards/{board}")]
+pub async fn edit_board(
+    pool: Data<PgPool>,
+    path: Path<String>,
+    identity: Identity,
+    info: Json<Value>,
+) -> Result<Json<Value>> {
+    require_admin(pool.as_ref(), &identity).await?;
+    let board = Board::fetch(pool.as_ref(), &path.into_inner())
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+
+    let changes = match info.into_inner() {
+        Value::Object(changes) => changes,
+        _ => return Err(RequestError::BadRequest("Expected an object".into())),
+    };
+    let mut merged =
+        serde_json::to_value(&board).map_err(|err| RequestError::Internal(err.into()))?;
+    if let Value::Object(merged) = &mut merged {
+        merged.extend(changes);
+    }
+    let mut edited: Board = serde_json::from_value(merged).map_err(RequestError::InvalidPayload)?;
+    edited.code = board.code;
+    validation::validate_board(&edited)?;
+
+    let edited = Board::update(pool.as_ref(), &edited)
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    board_response(edited)
+}
+
+#[delete("/boards/{board}")]
+pub async fn delete_board(
+    pool: Data<PgPool>,
+    rules: Data<RuleCache>,
+    path: Path<String>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_admin(pool.as_ref(), &identity).await?;
+    let code = path.into_inner();
+    let files = Board::delete(pool.as_ref(), &code)
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    multipart::remove(files).await;
+    rules.invalidate(&code);
+
+    Ok(Json(json!({ "success": true })))
+}
+
+#[get("/boards/{board}/rules")]
+pub async fn board_rules(
+    pool: Data<PgPool>,
+    path: Path<String>,
+    identity: Identity,
+) -> Result<Json<Vec<BoardRule>>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let rules = BoardRule::fetch_for_board(pool.as_ref(), &path.into_inner()).await?;
+    Ok(Json(rules))
+}
+
+#[post("/boards/{board}/rules")]
+pub async fn new_board_rule(
+    pool: Data<PgPool>,
+    rules: Data<RuleCache>,
+    path: Path<String>,
+    identity: Identity,
+    info: Json<NewRule>,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    validation::validate_rule(&info)?;
+    let board = Board::fetch(pool.as_ref(), &path.into_inner())
+        .await?
+        .ok_or(RequestError::BoardNotFound)?;
+    let info = info.into_inner();
+    let rule = BoardRuleNew {
+        field: info.field,
+        pattern: info.pattern,
+        action: info.action,
+        replacement: info.replacement.unwrap_or_default(),
+        reason: info.reason.unwrap_or_default(),
+        ban_minutes: info.ban_minutes,
+    };
+
+    // compile the rule once before saving it so broken patterns never reach the table
+    CompiledRule::compile(&BoardRule {
+        id: 0,
+        board: board.code.clone(),
+        field: rule.field.clone(),
+        pattern: rule.pattern.clone(),
+        action: rule.action.clone(),
+        replacement: rule.replacement.clone(),
+        reason: rule.reason.clone(),
+        ban_minutes: rule.ban_minutes,
+    })
+    .map_err(|err| RequestError::BadRequest(err.into()))?;
+
+    let rule = BoardRule::post(pool.as_ref(), &board.code, &rule).await?;
+    rules.invalidate(&board.code);
+
+    Ok(Json(json!({
+        "success": true,
+        "rule": rule
+    })))
+}
+
+#[delete("/rules/{rule}")]
+pub async fn delete_board_rule(
+    pool: Data<PgPool>,
+    rules: Data<RuleCache>,
+    path: Path<i32>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let rule = BoardRule::delete(pool.as_ref(), path.into_inner())
+        .await?
+        .ok_or(RequestError::NotFound)?;
+    rules.invalidate(&rule.board);
+
+    Ok(Json(json!({
+        "success": true,
+        "rule": rule
+    })))
+}
+
+#[get("/boards/{board}/pending")]
+pub async fn pending_posts(
+    pool: Data<PgPool>,
+    path: Path<String>,
+    identity: Identity,
+) -> Result<Json<Vec<Post>>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let posts = Post::fetch_pending(pool.as_ref(), &path.into_inner()).await?;
+    Ok(Json(posts))
+}
+
+#[post("/post/{post}/approve")]
+pub async fn approve_post(
+    pool: Data<PgPool>,
+    path: Path<i64>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let post = Post::approve(pool.as_ref(), path.into_inner())
+        .await?
+        .ok_or(RequestError::NotFound)?;
+
+    notify::publish(
+        pool.as_ref(),
+        &post.board,
+        post.thread,
+        EventKind::Post,
+        Some(post.id),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "success": true,
+        "post": post
+    })))
+}
+
+#[post("/post/{post}/reject")]
+pub async fn reject_post(
+    pool: Data<PgPool>,
+    path: Path<i64>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let rejected = Post::reject(pool.as_ref(), path.into_inner())
+        .await?
+        .ok_or(RequestError::NotFound)?;
+    multipart::remove(rejected.files).await;
+
+    // only staff and the author could see it, but they might have the thread open
+    notify::publish(
+        pool.as_ref(),
+        &rejected.board,
+        rejected.thread,
+        EventKind::Delete,
+        Some(rejected.id),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "success": true,
+        "id": rejected.id
+    })))
+}
+
+// replies and whole threads, pending or not, the files go with them
+#[delete("/post/{post}")]
+pub async fn delete_post(
+    pool: Data<PgPool>,
+    path: Path<i64>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let deleted = Post::delete(pool.as_ref(), path.into_inner())
+        .await?
+        .ok_or(RequestError::NotFound)?;
+    multipart::remove(deleted.files).await;
+
+    notify::publish(
+        pool.as_ref(),
+        &deleted.board,
+        deleted.thread,
+        EventKind::Delete,
+        Some(deleted.id),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "success": true,
+        "id": deleted.id,
+        "thread_deleted": deleted.opening
+    })))
+}
+
+#[put("/post/{post}")]
+pub async fn edit_post(
+    pool: Data<PgPool>,
+    path: Path<i64>,
+    identity: Identity,
+    info: Json<EditPost>,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    validation::validate_edit(&info)?;
+    let post = Post::edit(pool.as_ref(), path.into_inner(), &info.message)
+        .await?
+        .ok_or(RequestError::NotFound)?;
+
+    notify::publish(
+        pool.as_ref(),
+        &post.board,
+        post.thread,
+        EventKind::Edit,
+        Some(post.id),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "success": true,
+        "post": post
+    })))
+}
+
+#[delete("/post/{post}/image")]
+pub async fn remove_image(
+    pool: Data<PgPool>,
+    path: Path<i64>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let (post, files) = Post::remove_image(pool.as_ref(), path.into_inner())
+        .await?
+        .ok_or(RequestError::NotFound)?;
+    // otherwise the image would still be up at its old address
+    multipart::remove(files).await;
+
+    notify::publish(
+        pool.as_ref(),
+        &post.board,
+        post.thread,
+        EventKind::RemoveImage,
+        Some(post.id),
+    )
+    .await;
+
+    Ok(Json(json!({
+        "success": true,
+        "post": post
+    })))
+}
+
+#[post("/post/{post}/ban")]
+pub async fn ban_author(
+    pool: Data<PgPool>,
+    path: Path<i64>,
+    identity: Identity,
+    info: Json<NewBan>,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    validation::validate_ban(&info)?;
+    let post_id = path.into_inner();
+    let author = Post::fetch_author(pool.as_ref(), post_id)
+        .await?
+        .ok_or(RequestError::NotFound)?;
+    let info = info.into_inner();
+
+    let ban = Ban::post(
+        pool.as_ref(),
+        &BanNew {
+            identity: author.identity,
+            board: if info.global {
+                None
+            } else {
+                Some(author.board)
+            },
+            reason: info.reason.unwrap_or_default(),
+            minutes: info.minutes,
+            shadow: info.shadow,
+        },
+    )
+    .await?;
+
+    if let Some(message) = &info.message {
+        if let Some(post) = Post::set_ban_message(pool.as_ref(), post_id, message).await? {
+            notify::publish(
+                pool.as_ref(),
+                &post.board,
+                post.thread,
+                EventKind::BanMessage,
+                Some(post.id),
+            )
+            .await;
+        }
+    }
+
+    Ok(Json(json!({
+        "success": true,
+        "ban": ban
+    })))
+}
+
+#[delete("/bans/{ban}")]
+pub async fn delete_ban(
+    pool: Data<PgPool>,
+    path: Path<i32>,
+    identity: Identity,
+) -> Result<Json<Value>> {
+    require_staff(pool.as_ref(), &identity).await?;
+    let ban = Ban::delete(pool.as_ref(), path.into_inner())
+        .await?
+        .ok_or(RequestError::NotFound)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "ban": ban
+    })))
+}
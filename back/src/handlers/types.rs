@@ -1,12 +1,67 @@
+use crate::db::model::CatalogSort;
 use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct NewThread {
     pub name: Option<String>,
     pub message: String,
     pub title: Option<String>,
+    pub captcha: Option<String>,
 }
 #[derive(Deserialize)]
 pub struct NewPost {
     pub name: Option<String>,
     pub message: String,
+    pub captcha: Option<String>,
+}
+#[derive(Deserialize)]
+pub struct NewRule {
+    pub field: String,
+    pub pattern: String,
+    pub action: String,
+    pub replacement: Option<String>,
+    pub reason: Option<String>,
+    pub ban_minutes: Option<i32>,
+}
+#[derive(Deserialize)]
+pub struct NewBan {
+    pub reason: Option<String>,
+    // permanent if missing
+    pub minutes: Option<i32>,
+    #[serde(default)]
+    pub shadow: bool,
+    // ban from every board instead of just the one the post is on
+    #[serde(default)]
+    pub global: bool,
+    // shown publicly under the post, unlike the reason which only the author sees
+    pub message: Option<String>,
+}
+#[derive(Deserialize)]
+pub struct EditPost {
+    pub message: String,
+}
+#[derive(Deserialize)]
+pub struct MoveThread {
+    pub board: String,
+}
+#[derive(Deserialize)]
+pub struct CatalogQuery {
+    #[serde(default)]
+    pub sort: CatalogSort,
+}
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub board: Option<String>,
+    pub thread: Option<i32>,
+    pub has_image: Option<bool>,
+    // unix timestamps, in seconds
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub archived: Option<bool>,
+    pub cursor: Option<String>,
+}
+#[derive(Deserialize)]
+pub struct MarkRead {
+    // reply id, everything up to it gets marked, all of them if missing
+    pub until: Option<i64>,
 }
@@ -0,0 +1,243 @@
+use super::{error::RequestError, types::*, Result};
+use crate::db::model::{Board, SearchCursor};
+use crate::util::multipart::SavedFile;
+use serde::Serialize;
+
+pub const MAX_TITLE_LENGTH: usize = 100;
+pub const MAX_NAME_LENGTH: usize = 50;
+pub const MAX_MESSAGE_LENGTH: usize = 5000;
+pub const MAX_SEARCH_LENGTH: usize = 200;
+pub const MAX_BAN_MESSAGE_LENGTH: usize = 200;
+pub const MAX_BOARD_CODE_LENGTH: usize = 16;
+pub const MAX_BOARD_NAME_LENGTH: usize = 50;
+pub const MAX_BOARD_DESCRIPTION_LENGTH: usize = 500;
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum Violation {
+    Empty,
+    TooLong { max: usize },
+    TooSmall { min: i32 },
+    Invalid,
+    Taken,
+    NotAllowed,
+    UnsupportedType { allowed: String },
+    // in bytes
+    TooLarge { max: usize },
+    LimitReached { max: usize },
+    TextOnly,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    #[serde(flatten)]
+    pub violation: Violation,
+}
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.violation {
+            Violation::Empty => write!(f, "{} can't be empty", self.field),
+            Violation::TooLong { max } => {
+                write!(f, "{} can't be longer than {} characters", self.field, max)
+            }
+            Violation::TooSmall { min } => write!(f, "{} can't be less than {}", self.field, min),
+            Violation::Invalid => write!(f, "{} is invalid", self.field),
+            Violation::Taken => write!(f, "{} is already taken", self.field),
+            Violation::NotAllowed => write!(f, "{} isn't allowed here", self.field),
+            Violation::UnsupportedType { allowed } => {
+                write!(f, "{} should be one of {}", self.field, allowed)
+            }
+            Violation::TooLarge { max } => {
+                write!(f, "{} can't be larger than {} bytes", self.field, max)
+            }
+            Violation::TextOnly => write!(f, "{} is text only", self.field),
+            Violation::LimitReached { max } => {
+                write!(
+                    f,
+                    "this thread already has the maximum of {} {}s",
+                    max, self.field
+                )
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Violations(Vec<FieldError>);
+
+impl Violations {
+    pub fn push(&mut self, field: &'static str, violation: Violation) {
+        self.0.push(FieldError { field, violation });
+    }
+
+    fn not_empty(&mut self, field: &'static str, value: &str) {
+        if value.trim().is_empty() {
+            self.push(field, Violation::Empty);
+        }
+    }
+
+    fn max_length(&mut self, field: &'static str, value: &str, max: usize) {
+        if value.chars().count() > max {
+            self.push(field, Violation::TooLong { max });
+        }
+    }
+
+    fn min(&mut self, field: &'static str, value: i32, min: i32) {
+        if value < min {
+            self.push(field, Violation::TooSmall { min });
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(RequestError::Validation(self.0))
+        }
+    }
+}
+
+// checks that need nothing but the payload itself, run before anything touches the database
+pub trait Validate {
+    fn validate(&self, files: &[SavedFile]) -> Result<()>;
+}
+
+impl Validate for NewThread {
+    fn validate(&self, _files: &[SavedFile]) -> Result<()> {
+        let mut violations = Violations::default();
+        if let Some(title) = &self.title {
+            violations.max_length("title", title, MAX_TITLE_LENGTH);
+        }
+        if let Some(name) = &self.name {
+            violations.max_length("name", name, MAX_NAME_LENGTH);
+        }
+        violations.not_empty("message", &self.message);
+        violations.max_length("message", &self.message, MAX_MESSAGE_LENGTH);
+        violations.finish()
+    }
+}
+
+impl Validate for NewPost {
+    fn validate(&self, files: &[SavedFile]) -> Result<()> {
+        let mut violations = Violations::default();
+        if let Some(name) = &self.name {
+            violations.max_length("name", name, MAX_NAME_LENGTH);
+        }
+        // a reply can be just an image
+        if files.is_empty() {
+            violations.not_empty("message", &self.message);
+        }
+        violations.max_length("message", &self.message, MAX_MESSAGE_LENGTH);
+        violations.finish()
+    }
+}
+
+// the board's rules can rewrite a post, what comes out of them has to hold up as well
+// a reply can still be just an image
+pub fn validate_rewritten(
+    title: Option<&str>,
+    name: &str,
+    message: &str,
+    has_image: bool,
+) -> Result<()> {
+    let mut violations = Violations::default();
+    if let Some(title) = title {
+        violations.max_length("title", title, MAX_TITLE_LENGTH);
+    }
+    violations.max_length("name", name, MAX_NAME_LENGTH);
+    if !has_image {
+        violations.not_empty("message", message);
+    }
+    violations.max_length("message", message, MAX_MESSAGE_LENGTH);
+    violations.finish()
+}
+
+// settings that would break posting on the board, the database takes care of the rest
+pub fn validate_board(board: &Board) -> Result<()> {
+    let mut violations = Violations::default();
+    let code_ok = !board.code.is_empty()
+        && board.code.len() <= MAX_BOARD_CODE_LENGTH
+        && board
+            .code
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+    if !code_ok {
+        violations.push("code", Violation::Invalid);
+    }
+    violations.not_empty("name", &board.name);
+    violations.max_length("name", &board.name, MAX_BOARD_NAME_LENGTH);
+    violations.max_length(
+        "description",
+        &board.description,
+        MAX_BOARD_DESCRIPTION_LENGTH,
+    );
+    violations.not_empty("default_name", &board.default_name);
+    violations.max_length("default_name", &board.default_name, MAX_NAME_LENGTH);
+    match board.captcha.as_str() {
+        "never" | "always" | "threads" | "new_identities" => {}
+        _ => violations.push("captcha", Violation::Invalid),
+    }
+    if !board.text_only && board.allowed_media.trim().is_empty() {
+        violations.push("allowed_media", Violation::Empty);
+    }
+
+    violations.min("captcha_identity_age", board.captcha_identity_age, 0);
+    violations.min("pow_difficulty", board.pow_difficulty, 0);
+    violations.min("max_threads", board.max_threads, 1);
+    violations.min("bump_limit", board.bump_limit, 0);
+    violations.min("image_limit", board.image_limit, 0);
+    violations.min("max_file_size", board.max_file_size, 1);
+    let optional = [
+        ("premod_age", board.premod_age),
+        ("premod_posts", board.premod_posts),
+        ("r9k_mute_seconds", board.r9k_mute_seconds),
+        ("thread_cooldown", board.thread_cooldown),
+        ("reply_cooldown", board.reply_cooldown),
+        ("image_reply_cooldown", board.image_reply_cooldown),
+    ];
+    for (field, value) in optional.iter() {
+        if let Some(value) = value {
+            violations.min(*field, *value, 0);
+        }
+    }
+    violations.finish()
+}
+
+pub fn validate_search(query: &SearchQuery) -> Result<()> {
+    let mut violations = Violations::default();
+    violations.not_empty("q", &query.q);
+    violations.max_length("q", &query.q, MAX_SEARCH_LENGTH);
+    if let Some(cursor) = &query.cursor {
+        if SearchCursor::parse(cursor).is_none() {
+            violations.push("cursor", Violation::Invalid);
+        }
+    }
+    violations.finish()
+}
+
+pub fn validate_edit(edit: &EditPost) -> Result<()> {
+    let mut violations = Violations::default();
+    violations.not_empty("message", &edit.message);
+    violations.max_length("message", &edit.message, MAX_MESSAGE_LENGTH);
+    violations.finish()
+}
+
+// the pattern is checked by compiling it
+// a ban of zero minutes would be over before it started, a missing one is permanent
+pub fn validate_rule(rule: &NewRule) -> Result<()> {
+    let mut violations = Violations::default();
+    if let Some(minutes) = rule.ban_minutes {
+        violations.min("ban_minutes", minutes, 1);
+    }
+    violations.finish()
+}
+
+pub fn validate_ban(ban: &NewBan) -> Result<()> {
+    let mut violations = Violations::default();
+    if let Some(message) = &ban.message {
+        violations.not_empty("message", message);
+        violations.max_length("message", message, MAX_BAN_MESSAGE_LENGTH);
+    }
+    violations.finish()
+}
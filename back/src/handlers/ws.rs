@@ -0,0 +1,329 @@
+use super::error::RequestError;
+use super::types::NewPost;
+use super::{Poster, Posting, Result};
+use crate::db::model::{Board, Staff, ThreadWithPosts, Viewer};
+use crate::util::{
+    client_ip,
+    filters::RuleCache,
+    pow::{PowGuard, Solution},
+    rate_limit::RateLimiter,
+    sse_thread::{Broadcaster, Channel, Event, Frame, FrameItem},
+    GetIdentity,
+};
+use actix::{
+    Actor, ActorContext, ActorFuture, AsyncContext, SpawnHandle, StreamHandler, WrapFuture,
+};
+use actix_identity::Identity;
+use actix_web::{
+    get,
+    http::header,
+    web::{Data, Payload},
+    HttpRequest, HttpResponse,
+};
+use actix_web_actors::ws;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+// one socket can follow this many threads and boards at once
+const MAX_SUBSCRIPTIONS: usize = 50;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// no pong or anything else for this long and the connection is considered dead
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(90);
+
+// the same channels the sse endpoints serve, written the way the server sends them back:
+// {"thread": 1}, {"board": "b"} or "overboard"
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Target {
+    Thread(i32),
+    Board(String),
+    Overboard,
+}
+
+impl From<Target> for Channel {
+    fn from(target: Target) -> Self {
+        match target {
+            Target::Thread(id) => Channel::Thread(id),
+            Target::Board(code) => Channel::Board(code),
+            Target::Overboard => Channel::Overboard,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        channel: Target,
+    },
+    Unsubscribe {
+        channel: Target,
+    },
+    // text only, images still go through the form
+    Post {
+        thread: i32,
+        post: NewPost,
+        pow: Option<Solution>,
+    },
+}
+
+// everything the server sends is json with a `type`:
+// event, subscribed, unsubscribed, posted or error
+// replies and watched threads come in on the "me" channel without asking
+struct WsSession {
+    brd: Data<Broadcaster>,
+    posting: Posting,
+    identity: String,
+    ip: String,
+    viewer: Viewer,
+    subscriptions: HashMap<Channel, SpawnHandle>,
+    heartbeat: Instant,
+}
+
+impl Actor for WsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |session, ctx| {
+            if Instant::now().duration_since(session.heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+
+        self.open_me(ctx);
+    }
+}
+
+impl WsSession {
+    // not in `subscriptions`, it can't be unsubscribed from
+    fn open_me(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let me = Channel::Me(self.identity.clone());
+        match self.brd.join_channel(me, &self.ip) {
+            Ok(joined) => {
+                ctx.add_stream(joined.frames(Vec::new(), HashSet::new(), self.viewer.clone()));
+            }
+            Err(err) => Self::error(ctx, err.into()),
+        }
+    }
+
+    fn error(ctx: &mut ws::WebsocketContext<Self>, err: RequestError) {
+        if let RequestError::Internal(err) = &err {
+            eprintln!("Internal error: {}", err);
+        }
+        let mut body = err.body();
+        body["type"] = "error".into();
+        ctx.text(body.to_string());
+    }
+
+    fn handle_message(&mut self, message: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        match message {
+            ClientMessage::Subscribe { channel } => self.subscribe(channel.into(), ctx),
+            ClientMessage::Unsubscribe { channel } => {
+                let channel: Channel = channel.into();
+                if let Some(handle) = self.subscriptions.remove(&channel) {
+                    // dropping the stream drops the subscription with it
+                    ctx.cancel_future(handle);
+                }
+                let reply = json!({ "type": "unsubscribed", "channel": channel.describe() });
+                ctx.text(reply.to_string());
+            }
+            ClientMessage::Post { thread, post, pow } => {
+                // like the forms, checked before anything is looked up
+                let guard = &self.posting.pow_guard;
+                let verified = pow.map(|pow| guard.verify(&pow, &self.identity));
+                let proof = match verified.transpose() {
+                    Ok(proof) => proof,
+                    Err(err) => return Self::error(ctx, err.into()),
+                };
+                let posting = self.posting.clone();
+                let poster = Poster {
+                    identity: self.identity.clone(),
+                    proof,
+                };
+                let ip = self.ip.clone();
+                let submit = async move { posting.reply(thread, poster, &ip, post).await };
+                ctx.spawn(submit.into_actor(self).map(|result, _, ctx| match result {
+                    Ok(post) => ctx.text(json!({ "type": "posted", "post": post }).to_string()),
+                    Err(err) => Self::error(ctx, err),
+                }));
+            }
+        }
+    }
+
+    fn subscribe(&mut self, channel: Channel, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.subscriptions.contains_key(&channel)
+            && self.subscriptions.len() >= MAX_SUBSCRIPTIONS
+        {
+            let err = RequestError::BadRequest("Too many subscriptions".into());
+            return Self::error(ctx, err);
+        }
+
+        // joined before the snapshot is read, like the sse endpoints do, posts sent in
+        // the meantime wait and the ones that made it into the snapshot only go out once
+        let joined = match self.brd.join_channel(channel.clone(), &self.ip) {
+            Ok(joined) => joined,
+            Err(err) => return Self::error(ctx, err.into()),
+        };
+        let pool = self.posting.pool.clone();
+        let viewer = self.viewer.clone();
+        let target = channel.clone();
+        let initial = async move { snapshot(pool.as_ref(), &target, &viewer).await };
+        let subscribed = channel.clone();
+        let handle = ctx.spawn(initial.into_actor(self).map(move |result, session, ctx| {
+            let (initial, replayed) = match result {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    session.subscriptions.remove(&subscribed);
+                    return Self::error(ctx, err);
+                }
+            };
+            let reply = json!({ "type": "subscribed", "channel": subscribed.describe() });
+            ctx.text(reply.to_string());
+            let frames = joined.frames(initial, replayed, session.viewer.clone());
+            let handle = ctx.add_stream(frames);
+            session.subscriptions.insert(subscribed, handle);
+        }));
+        // the slot is taken while the snapshot loads, so a burst of subscribes can't
+        // get past the limit, subscribing twice just starts over
+        if let Some(previous) = self.subscriptions.insert(channel, handle) {
+            ctx.cancel_future(previous);
+        }
+    }
+}
+
+// what the client gets first along with the posts in it, and the check that there's
+// anything to subscribe to
+async fn snapshot(
+    pool: &PgPool,
+    channel: &Channel,
+    viewer: &Viewer,
+) -> Result<(Vec<Frame>, HashSet<i64>)> {
+    match channel {
+        Channel::Thread(id) => {
+            let thread = ThreadWithPosts::fetch(pool, *id, viewer)
+                .await?
+                .ok_or(RequestError::ThreadNotFound)?;
+            let replayed = thread.post_ids().collect();
+            Ok((vec![Event::Thread(&thread).to_frame()], replayed))
+        }
+        Channel::Board(code) => {
+            Board::fetch(pool, code)
+                .await?
+                .ok_or(RequestError::BoardNotFound)?;
+            Ok((Vec::new(), HashSet::new()))
+        }
+        Channel::Overboard | Channel::Me(_) => Ok((Vec::new(), HashSet::new())),
+    }
+}
+
+impl StreamHandler<std::result::Result<ws::Message, ws::ProtocolError>> for WsSession {
+    fn handle(
+        &mut self,
+        message: std::result::Result<ws::Message, ws::ProtocolError>,
+        ctx: &mut Self::Context,
+    ) {
+        let message = match message {
+            Ok(message) => message,
+            Err(_) => return ctx.stop(),
+        };
+        self.heartbeat = Instant::now();
+        match message {
+            ws::Message::Ping(bytes) => ctx.pong(&bytes),
+            ws::Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(message) => self.handle_message(message, ctx),
+                Err(err) => Self::error(ctx, RequestError::InvalidPayload(err)),
+            },
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Binary(_) => {
+                Self::error(ctx, RequestError::BadRequest("Expected text".into()))
+            }
+            ws::Message::Pong(_) | ws::Message::Continuation(_) | ws::Message::Nop => {}
+        }
+    }
+}
+
+// events from the broadcaster, already serialized
+// a channel's stream ends when the client couldn't keep up or the server is going down,
+// the slot is given back and the client told, "me" is opened again right away
+impl StreamHandler<FrameItem> for WsSession {
+    fn handle(&mut self, item: FrameItem, ctx: &mut Self::Context) {
+        match item {
+            FrameItem::Text(text) => ctx.text(text),
+            FrameItem::Ended(Channel::Me(_)) => self.open_me(ctx),
+            FrameItem::Ended(channel) => {
+                self.subscriptions.remove(&channel);
+                let reply = json!({ "type": "unsubscribed", "channel": channel.describe() });
+                ctx.text(reply.to_string());
+            }
+        }
+    }
+
+    // one subscription ending doesn't end the session
+    fn finished(&mut self, _: &mut Self::Context) {}
+}
+
+// browsers send the cookie along with cross-site handshakes, without this any page
+// could post and read replies as whoever happens to open it
+fn origin_allowed(req: &HttpRequest) -> bool {
+    let origin = match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin.to_str().unwrap_or_default(),
+        // not a browser
+        None => return true,
+    };
+    if origin.is_empty() {
+        return false;
+    }
+    let host = origin.splitn(2, "://").nth(1).unwrap_or_default();
+    host == req.connection_info().host()
+        || crate::CONFIG
+            .allowed_origins
+            .split(',')
+            .any(|allowed| allowed.trim() == origin)
+}
+
+#[get("/ws")]
+pub async fn websocket(
+    brd: Data<Broadcaster>,
+    pool: Data<PgPool>,
+    limiter: Data<RateLimiter>,
+    pow_guard: Data<PowGuard>,
+    rules: Data<RuleCache>,
+    identity: Identity,
+    req: HttpRequest,
+    stream: Payload,
+) -> std::result::Result<HttpResponse, actix_web::Error> {
+    if !origin_allowed(&req) {
+        return Err(RequestError::OriginNotAllowed.into());
+    }
+    // replies are tied to the identity, so the session needs one from the start
+    let identity = identity.get();
+    let viewer = Viewer {
+        staff: Staff::is_staff(pool.as_ref(), &identity)
+            .await
+            .map_err(RequestError::from)?,
+        identity: Some(identity.clone()),
+    };
+    let session = WsSession {
+        brd,
+        posting: Posting {
+            pool,
+            limiter,
+            pow_guard,
+            rules,
+        },
+        identity,
+        ip: client_ip(&req),
+        viewer,
+        subscriptions: HashMap::new(),
+        heartbeat: Instant::now(),
+    };
+    ws::start(session, &req, stream)
+}
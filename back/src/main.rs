@@ -4,12 +4,19 @@ mod handlers;
 mod util;
 
 use actix_identity::{CookieIdentityPolicy, IdentityService};
-use actix_web::{web::route, App, HttpResponse, HttpServer};
+use actix_web::{cookie::SameSite, web::route, App, HttpResponse, HttpServer};
 use colored::Colorize;
 use config::Config;
-use handlers::{boards, catalog, new_post, new_thread, thread_subscribe};
+use handlers::{
+    approve_post, ban_author, board_page, board_post, board_rules, board_subscribe, boards,
+    catalog, delete_ban, delete_board, delete_board_rule, delete_post, edit_board, edit_post,
+    lock_thread, me_subscribe, move_thread, my_replies, my_watches, new_board, new_board_rule,
+    new_captcha, new_post, new_thread, overboard_subscribe, pending_posts, pow_challenge,
+    read_replies, reject_post, remove_image, search, sticky_thread, stream_stats, thread_subscribe,
+    thread_subscribers, unlock_thread, unsticky_thread, unwatch_thread, watch_thread, websocket,
+};
 use lazy_static::lazy_static;
-use util::sse_thread::Broadcaster;
+use util::{filters::RuleCache, pow::PowGuard, rate_limit::RateLimiter, sse_thread::Broadcaster};
 
 lazy_static! {
     static ref CONFIG: Config = Config::create();
@@ -26,7 +33,10 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    let broadcaster = Broadcaster::create();
+    let broadcaster = Broadcaster::create(pool.clone());
+    let limiter = RateLimiter::create(pool.clone());
+    let pow_guard = PowGuard::create();
+    let rules = RuleCache::create();
 
     CONFIG.print();
 
@@ -34,17 +44,56 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .data(pool.clone())
             .app_data(broadcaster.clone())
+            .app_data(limiter.clone())
+            .app_data(pow_guard.clone())
+            .app_data(rules.clone())
             .wrap(IdentityService::new(
                 CookieIdentityPolicy::new(&CONFIG.private_key.clone().into_bytes())
                     .name("sid")
                     .path("/")
+                    .same_site(SameSite::Lax)
                     .secure(CONFIG.https),
             ))
             .service(boards)
+            .service(new_board)
+            .service(edit_board)
+            .service(delete_board)
             .service(catalog)
+            .service(board_page)
+            .service(board_post)
+            .service(search)
             .service(new_thread)
+            .service(board_subscribe)
+            .service(overboard_subscribe)
+            .service(stream_stats)
+            .service(thread_subscribers)
             .service(thread_subscribe)
+            .service(me_subscribe)
+            .service(my_replies)
+            .service(read_replies)
+            .service(my_watches)
+            .service(watch_thread)
+            .service(unwatch_thread)
+            .service(websocket)
             .service(new_post)
+            .service(new_captcha)
+            .service(pow_challenge)
+            .service(sticky_thread)
+            .service(unsticky_thread)
+            .service(lock_thread)
+            .service(unlock_thread)
+            .service(move_thread)
+            .service(board_rules)
+            .service(new_board_rule)
+            .service(delete_board_rule)
+            .service(pending_posts)
+            .service(approve_post)
+            .service(reject_post)
+            .service(delete_post)
+            .service(edit_post)
+            .service(remove_image)
+            .service(ban_author)
+            .service(delete_ban)
             .default_service(route().to(|| HttpResponse::MethodNotAllowed()))
     })
     .bind(&CONFIG.address)?
@@ -0,0 +1,132 @@
+use image::{png::PngEncoder, ColorType, ImageError};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+// letters that are easy to confuse with each other (B/8, S/5, Z/2, 6/G, 9/g, O/0, I/1) are left out
+const ALPHABET: &[u8] = b"ACDEFHKMNPRTUVWXY234578";
+const LENGTH: usize = 5;
+
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 60;
+const SCALE: i32 = 4;
+
+// 5x7 bitmap glyphs, one byte per row, the highest of the 5 bits is the leftmost pixel
+fn glyph(c: u8) -> [u8; 7] {
+    match c {
+        b'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        b'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        b'D' => [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E],
+        b'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        b'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        b'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        b'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        b'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        b'N' => [0x11, 0x19, 0x15, 0x13, 0x11, 0x11, 0x11],
+        b'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        b'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        b'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        b'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        b'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        b'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        b'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        b'Y' => [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04],
+        b'2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        b'3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        b'4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        b'5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        b'7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        b'8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        _ => [0; 7],
+    }
+}
+
+pub struct Challenge {
+    pub answer: String,
+    pub png: Vec<u8>,
+}
+
+// answers are compared case-insensitively and salted with the private key,
+// so the stored hashes are useless without it
+pub fn hash_answer(answer: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(crate::CONFIG.private_key.as_bytes());
+    hasher.update(answer.trim().to_uppercase().as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// renders the answer with a per-letter offset and shear, warps the whole thing with
+// a couple of sine waves and then sprinkles some noise and lines on top
+// CPU bound, should be run with web::block
+pub fn generate() -> Result<Challenge, ImageError> {
+    let mut rng = rand::thread_rng();
+    let answer: String = (0..LENGTH)
+        .map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())] as char)
+        .collect();
+
+    let (w, h) = (WIDTH as i32, HEIGHT as i32);
+    let mut text = vec![255u8; (WIDTH * HEIGHT) as usize];
+    let mut x = 10 + rng.gen_range(0, 8);
+    for c in answer.bytes() {
+        let y = rng.gen_range(6, h - 7 * SCALE - 6);
+        let shear: f32 = rng.gen_range(-0.35, 0.35);
+        let ink = rng.gen_range(0, 90) as u8;
+        for (gy, row) in glyph(c).iter().enumerate() {
+            for gx in 0..5 {
+                if row & (0x10 >> gx) == 0 {
+                    continue;
+                }
+                let shift = ((gy as i32 * SCALE) as f32 * shear) as i32;
+                for dy in 0..SCALE {
+                    for dx in 0..SCALE {
+                        let px = x + gx * SCALE + dx - shift;
+                        let py = y + gy as i32 * SCALE + dy;
+                        if px >= 0 && px < w && py >= 0 && py < h {
+                            text[(py * w + px) as usize] = ink;
+                        }
+                    }
+                }
+            }
+        }
+        x += 5 * SCALE + rng.gen_range(4, 10);
+    }
+
+    let amplitude_x: f32 = rng.gen_range(2.0, 4.0);
+    let amplitude_y: f32 = rng.gen_range(2.0, 5.0);
+    let period_x: f32 = rng.gen_range(8.0, 14.0);
+    let period_y: f32 = rng.gen_range(14.0, 24.0);
+    let phase: f32 = rng.gen_range(0.0, std::f32::consts::PI * 2.0);
+    let mut pixels = vec![255u8; (WIDTH * HEIGHT) as usize];
+    for py in 0..h {
+        for px in 0..w {
+            let sx = px + (amplitude_x * (py as f32 / period_x + phase).sin()) as i32;
+            let sy = py + (amplitude_y * (px as f32 / period_y + phase).sin()) as i32;
+            if sx >= 0 && sx < w && sy >= 0 && sy < h {
+                pixels[(py * w + px) as usize] = text[(sy * w + sx) as usize];
+            }
+        }
+    }
+
+    for pixel in pixels.iter_mut() {
+        if rng.gen_bool(0.06) {
+            *pixel = rng.gen_range(0, 256) as u8;
+        }
+    }
+    for _ in 0..3 {
+        let (x0, y0) = (rng.gen_range(0, w) as f32, rng.gen_range(0, h) as f32);
+        let (x1, y1) = (rng.gen_range(0, w) as f32, rng.gen_range(0, h) as f32);
+        let ink = rng.gen_range(0, 120) as u8;
+        for step in 0..w * 2 {
+            let t = step as f32 / (w * 2) as f32;
+            let px = (x0 + (x1 - x0) * t) as i32;
+            let py = (y0 + (y1 - y0) * t) as i32;
+            if px >= 0 && px < w && py >= 0 && py < h {
+                pixels[(py * w + px) as usize] = ink;
+            }
+        }
+    }
+
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png).encode(&pixels, WIDTH, HEIGHT, ColorType::L8)?;
+
+    Ok(Challenge { answer, png })
+}
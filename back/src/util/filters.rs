@@ -0,0 +1,327 @@
+use crate::db::model::BoardRule;
+use actix_web::web::Data;
+use colored::Colorize;
+use regex::Regex;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+// other instances can change the rules too, so cached rules are reloaded after a while
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Field {
+    Message,
+    Name,
+    Title,
+    Any,
+}
+
+impl Field {
+    pub fn parse(field: &str) -> Option<Self> {
+        match field {
+            "message" => Some(Self::Message),
+            "name" => Some(Self::Name),
+            "title" => Some(Self::Title),
+            "any" => Some(Self::Any),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, other: Field) -> bool {
+        *self == Field::Any || *self == other
+    }
+}
+
+pub enum Action {
+    Replace(String),
+    Reject(String),
+    Ban {
+        reason: String,
+        minutes: Option<i32>,
+    },
+    Hold,
+}
+
+impl Action {
+    pub fn parse(rule: &BoardRule) -> Option<Self> {
+        match rule.action.as_str() {
+            "replace" => Some(Self::Replace(rule.replacement.clone())),
+            "reject" => Some(Self::Reject(rule.reason.clone())),
+            "ban" => Some(Self::Ban {
+                reason: rule.reason.clone(),
+                minutes: rule.ban_minutes,
+            }),
+            "hold" => Some(Self::Hold),
+            _ => None,
+        }
+    }
+}
+
+pub struct CompiledRule {
+    field: Field,
+    regex: Regex,
+    action: Action,
+}
+
+impl CompiledRule {
+    pub fn compile(rule: &BoardRule) -> Result<Self, String> {
+        let field =
+            Field::parse(&rule.field).ok_or_else(|| format!("unknown field {}", rule.field))?;
+        let action =
+            Action::parse(rule).ok_or_else(|| format!("unknown action {}", rule.action))?;
+        let regex = Regex::new(&rule.pattern).map_err(|err| err.to_string())?;
+        Ok(CompiledRule {
+            field,
+            regex,
+            action,
+        })
+    }
+}
+
+// the text fields of a post, as they'll be stored
+pub struct Fields<'a> {
+    pub message: &'a mut String,
+    pub name: &'a mut String,
+    pub title: Option<&'a mut String>,
+}
+
+pub enum Verdict {
+    Accept,
+    Hold,
+    Reject(String),
+    Ban {
+        reason: String,
+        minutes: Option<i32>,
+    },
+}
+
+// rules run in the order they were added, replacements apply to whatever the
+// previous rules left and the first reject or ban stops everything
+pub fn apply(rules: &[CompiledRule], fields: Fields) -> Verdict {
+    let Fields {
+        message,
+        name,
+        mut title,
+    } = fields;
+    let mut hold = false;
+
+    for rule in rules.iter() {
+        let mut targets: Vec<&mut String> = Vec::with_capacity(3);
+        if rule.field.matches(Field::Message) {
+            targets.push(&mut *message);
+        }
+        if rule.field.matches(Field::Name) {
+            targets.push(&mut *name);
+        }
+        if rule.field.matches(Field::Title) {
+            if let Some(title) = title.as_mut() {
+                targets.push(&mut **title);
+            }
+        }
+
+        for target in targets {
+            if !rule.regex.is_match(target) {
+                continue;
+            }
+            match &rule.action {
+                Action::Replace(replacement) => {
+                    *target = rule
+                        .regex
+                        .replace_all(target, replacement.as_str())
+                        .into_owned();
+                }
+                Action::Reject(reason) => return Verdict::Reject(reason.clone()),
+                Action::Ban { reason, minutes } => {
+                    return Verdict::Ban {
+                        reason: reason.clone(),
+                        minutes: *minutes,
+                    }
+                }
+                Action::Hold => hold = true,
+            }
+        }
+    }
+
+    if hold {
+        Verdict::Hold
+    } else {
+        Verdict::Accept
+    }
+}
+
+struct CachedRules {
+    rules: Arc<Vec<CompiledRule>>,
+    loaded: Instant,
+}
+
+pub struct RuleCache(RwLock<HashMap<String, CachedRules>>);
+
+impl RuleCache {
+    pub fn create() -> Data<RuleCache> {
+        Data::new(RuleCache(RwLock::new(HashMap::new())))
+    }
+
+    pub async fn rules(&self, pool: &PgPool, board: &str) -> sqlx::Result<Arc<Vec<CompiledRule>>> {
+        if let Some(cached) = self.0.read().unwrap().get(board) {
+            if cached.loaded.elapsed() < CACHE_TTL {
+                return Ok(cached.rules.clone());
+            }
+        }
+
+        let rules: Vec<CompiledRule> = BoardRule::fetch_for_board(pool, board)
+            .await?
+            .iter()
+            .filter_map(|rule| match CompiledRule::compile(rule) {
+                Ok(compiled) => Some(compiled),
+                // the api doesn't let broken rules in, but someone could've edited the table by hand
+                Err(err) => {
+                    eprintln!("{}: Skipping rule {}: {}", "Warning".yellow(), rule.id, err);
+                    None
+                }
+            })
+            .collect();
+        let rules = Arc::new(rules);
+
+        self.0.write().unwrap().insert(
+            board.to_owned(),
+            CachedRules {
+                rules: rules.clone(),
+                loaded: Instant::now(),
+            },
+        );
+        Ok(rules)
+    }
+
+    pub fn invalidate(&self, board: &str) {
+        self.0.write().unwrap().remove(board);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(field: &str, pattern: &str, action: &str) -> BoardRule {
+        BoardRule {
+            id: 1,
+            board: "b".to_owned(),
+            field: field.to_owned(),
+            pattern: pattern.to_owned(),
+            action: action.to_owned(),
+            replacement: "***".to_owned(),
+            reason: "spam".to_owned(),
+            ban_minutes: Some(60),
+        }
+    }
+
+    fn compile(rules: &[BoardRule]) -> Vec<CompiledRule> {
+        rules
+            .iter()
+            .map(|rule| CompiledRule::compile(rule).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn broken_rules_dont_compile() {
+        assert!(CompiledRule::compile(&rule("email", "x", "reject")).is_err());
+        assert!(CompiledRule::compile(&rule("message", "x", "delete")).is_err());
+        assert!(CompiledRule::compile(&rule("message", "(x", "reject")).is_err());
+        assert!(CompiledRule::compile(&rule("any", "x", "hold")).is_ok());
+    }
+
+    #[test]
+    fn replacements_only_touch_their_field() {
+        let rules = compile(&[rule("message", "bad", "replace")]);
+        let (mut message, mut name, mut title) =
+            ("bad bad".to_owned(), "bad".to_owned(), "bad".to_owned());
+        let verdict = apply(
+            &rules,
+            Fields {
+                message: &mut message,
+                name: &mut name,
+                title: Some(&mut title),
+            },
+        );
+        assert!(matches!(verdict, Verdict::Accept));
+        assert_eq!(message, "*** ***");
+        assert_eq!(name, "bad");
+        assert_eq!(title, "bad");
+    }
+
+    #[test]
+    fn any_covers_every_field() {
+        let rules = compile(&[rule("any", "bad", "replace")]);
+        let (mut message, mut name, mut title) =
+            ("bad".to_owned(), "bad".to_owned(), "bad".to_owned());
+        apply(
+            &rules,
+            Fields {
+                message: &mut message,
+                name: &mut name,
+                title: Some(&mut title),
+            },
+        );
+        assert_eq!((message.as_str(), name.as_str()), ("***", "***"));
+        assert_eq!(title, "***");
+    }
+
+    #[test]
+    fn later_rules_see_replacements() {
+        let rules = compile(&[
+            rule("message", "bad", "replace"),
+            rule("message", "bad", "reject"),
+        ]);
+        let (mut message, mut name) = ("bad".to_owned(), String::new());
+        let verdict = apply(
+            &rules,
+            Fields {
+                message: &mut message,
+                name: &mut name,
+                title: None,
+            },
+        );
+        assert!(matches!(verdict, Verdict::Accept));
+    }
+
+    #[test]
+    fn first_reject_or_ban_wins() {
+        let rules = compile(&[
+            rule("message", "spam", "hold"),
+            rule("message", "spam", "ban"),
+            rule("message", "spam", "reject"),
+        ]);
+        let (mut message, mut name) = ("spam".to_owned(), String::new());
+        let verdict = apply(
+            &rules,
+            Fields {
+                message: &mut message,
+                name: &mut name,
+                title: None,
+            },
+        );
+        assert!(matches!(
+            verdict,
+            Verdict::Ban { ref reason, minutes: Some(60) } if reason == "spam"
+        ));
+    }
+
+    #[test]
+    fn holds_when_nothing_else_matches() {
+        let rules = compile(&[
+            rule("name", "^new$", "hold"),
+            rule("message", "spam", "reject"),
+        ]);
+        let (mut message, mut name) = ("hello".to_owned(), "new".to_owned());
+        let verdict = apply(
+            &rules,
+            Fields {
+                message: &mut message,
+                name: &mut name,
+                title: None,
+            },
+        );
+        assert!(matches!(verdict, Verdict::Hold));
+    }
+}
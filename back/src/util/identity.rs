@@ -1,6 +1,8 @@
 use actix_identity::Identity;
+use actix_web::HttpRequest;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use std::net::SocketAddr;
 
 pub trait GetIdentity {
     fn get(&self) -> String;
@@ -21,3 +23,47 @@ impl GetIdentity for Identity {
         }
     }
 }
+
+// the address the request came from, without the port
+// the proxy's headers are only believed with TRUST_PROXY, see Config
+pub fn client_ip(req: &HttpRequest) -> String {
+    address(req, crate::CONFIG.trust_proxy)
+}
+
+fn address(req: &HttpRequest, trust_proxy: bool) -> String {
+    if !trust_proxy {
+        return match req.peer_addr() {
+            Some(socket) => socket.ip().to_string(),
+            None => "unknown".to_owned(),
+        };
+    }
+    let info = req.connection_info();
+    let addr = info.realip_remote_addr().unwrap_or("unknown");
+    match addr.parse::<SocketAddr>() {
+        Ok(socket) => socket.ip().to_string(),
+        Err(_) => addr.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn forwarded() -> HttpRequest {
+        TestRequest::default()
+            .peer_addr("10.0.0.1:4321".parse().unwrap())
+            .header("x-forwarded-for", "1.2.3.4")
+            .to_http_request()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_by_default() {
+        assert_eq!(address(&forwarded(), false), "10.0.0.1");
+    }
+
+    #[test]
+    fn forwarded_for_is_used_behind_a_proxy() {
+        assert_eq!(address(&forwarded(), true), "1.2.3.4");
+    }
+}
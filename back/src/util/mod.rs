@@ -1,5 +1,11 @@
+pub mod captcha;
+pub mod filters;
 mod identity;
 pub mod multipart;
+pub mod notify;
+pub mod pow;
+pub mod r9k;
+pub mod rate_limit;
 pub mod sse_thread;
 
-pub use identity::GetIdentity;
+pub use identity::{client_ip, GetIdentity};
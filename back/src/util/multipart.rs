@@ -1,17 +1,27 @@
+use crate::db::model::Board;
 use actix_multipart::{Field, Multipart};
 use actix_web::web::{block, Bytes};
+use image::ImageFormat;
 use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 use std::ffi::OsStr;
 use std::io::Write;
 use std::path::Path;
 use tokio::stream::StreamExt;
 
+// enough of the start of a file to tell any of the formats below apart
+const SNIFF_LEN: usize = 16;
+
 #[derive(Debug)]
 pub enum MultipartError {
     Decode,
     Internal(String),
     BadRequest,
-    InvalidField(String),
+    InvalidField(serde_json::Error),
+    // the board's file settings, checked before anything is written past them
+    FileNotAllowed,
+    UnsupportedType(String),
+    FileTooLarge(usize),
 }
 impl std::fmt::Display for MultipartError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -23,6 +33,9 @@ impl std::fmt::Display for MultipartError {
             ),
             Self::BadRequest => "bad request".to_owned(),
             Self::InvalidField(info) => format!("invalid payload structure: {}", info),
+            Self::FileNotAllowed => "files aren't allowed here".to_owned(),
+            Self::UnsupportedType(allowed) => format!("file should be one of {}", allowed),
+            Self::FileTooLarge(max) => format!("file can't be larger than {} bytes", max),
         };
         write!(f, "{}", message)
     }
@@ -40,12 +53,43 @@ async fn field_to_string(field: Field) -> Result<String, MultipartError> {
         .map_err(|_| MultipartError::BadRequest)
 }
 
+// what the file really is going by its first bytes, the client's content type can say anything
+fn sniff_media(head: &[u8]) -> Option<&'static str> {
+    match image::guess_format(head).ok()? {
+        ImageFormat::Png => Some("image/png"),
+        ImageFormat::Jpeg => Some("image/jpeg"),
+        ImageFormat::Gif => Some("image/gif"),
+        ImageFormat::WebP => Some("image/webp"),
+        _ => None,
+    }
+}
+
+fn check_media(board: &Board, head: &[u8]) -> Result<(), MultipartError> {
+    match sniff_media(head) {
+        Some(media) if board.allows_media(media) => Ok(()),
+        _ => Err(MultipartError::UnsupportedType(board.allowed_media.clone())),
+    }
+}
+
 pub struct SavedFile {
     pub name: String,
     pub path: String,
+    // sha256 of the contents
+    pub hash: Vec<u8>,
+    // width and height, None if the file isn't an image we can decode
+    pub dimensions: Option<(u32, u32)>,
 }
 
-async fn field_to_file(mut field: Field, directory: &str) -> Result<SavedFile, MultipartError> {
+async fn field_to_file(
+    mut field: Field,
+    directory: &str,
+    board: &Board,
+) -> Result<SavedFile, MultipartError> {
+    if board.text_only {
+        return Err(MultipartError::FileNotAllowed);
+    }
+    let max_size = board.max_file_size.max(0) as usize;
+
     let filename = field
         .content_disposition()
         .map(|cd| cd.get_filename().map(|str| str.to_owned()))
@@ -73,44 +117,145 @@ async fn field_to_file(mut field: Field, directory: &str) -> Result<SavedFile, M
         .await
         .map_err(|err| MultipartError::Internal(err.to_string()))?;
 
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    // checked as soon as there's enough of it, short files once they're in
+    let mut head = Vec::with_capacity(SNIFF_LEN);
     while let Some(chunk) = field.next().await {
-        let data = chunk.map_err(|_| MultipartError::Decode)?;
-        f = block(move || f.write_all(&data).map(|_| f))
-            .await
-            .map_err(|err| MultipartError::Internal(err.to_string()))?;
+        let data = match chunk {
+            Ok(data) => data,
+            Err(_) => return Err(abandon(filepath, MultipartError::Decode).await),
+        };
+        size += data.len();
+        if size > max_size {
+            return Err(abandon(filepath, MultipartError::FileTooLarge(max_size)).await);
+        }
+        if head.len() < SNIFF_LEN {
+            let missing = (SNIFF_LEN - head.len()).min(data.len());
+            head.extend_from_slice(&data[..missing]);
+            if head.len() == SNIFF_LEN {
+                if let Err(err) = check_media(board, &head) {
+                    return Err(abandon(filepath, err).await);
+                }
+            }
+        }
+        hasher.update(&data);
+        f = match block(move || f.write_all(&data).map(|_| f)).await {
+            Ok(f) => f,
+            Err(err) => {
+                let err = MultipartError::Internal(err.to_string());
+                return Err(abandon(filepath, err).await);
+            }
+        };
     }
 
+    if head.len() < SNIFF_LEN {
+        if let Err(err) = check_media(board, &head) {
+            return Err(abandon(filepath, err).await);
+        }
+    }
+
+    // only reads the header, not the whole image
+    let cln = filepath.clone();
+    let dimensions = block(move || image::image_dimensions(cln)).await.ok();
+
     Ok(SavedFile {
         name: filename,
         path: filepath,
+        hash: hasher.finalize().to_vec(),
+        dimensions,
+    })
+}
+
+// a partly written file is no use to anyone
+async fn abandon(path: String, err: MultipartError) -> MultipartError {
+    remove(vec![path]).await;
+    err
+}
+
+// also used for files of posts that are gone
+pub async fn remove(paths: Vec<String>) {
+    let removed = block(move || {
+        for path in paths.iter() {
+            std::fs::remove_file(path)?;
+        }
+        Ok::<_, std::io::Error>(())
     })
+    .await;
+    if let Err(err) = removed {
+        eprintln!("Couldn't remove uploaded file: {}", err);
+    }
 }
 
+// uploads that didn't make it into a post, nothing points at them
+pub async fn discard(files: Vec<SavedFile>) {
+    if !files.is_empty() {
+        remove(files.into_iter().map(|file| file.path).collect()).await;
+    }
+}
+
+// only the first file ends up in a post, so that's the only one we save
 pub async fn to_payload<T: DeserializeOwned>(
     mut mp: Multipart,
+    board: &Board,
 ) -> Result<(T, Vec<SavedFile>), MultipartError> {
     let mut payload: Option<String> = None;
     let mut files: Vec<SavedFile> = Vec::new();
 
-    while let Ok(Some(field)) = mp.try_next().await {
-        let disposition = field.content_disposition().ok_or(MultipartError::Decode)?;
-        let field_name = disposition.get_name().ok_or(MultipartError::Decode)?;
-        match field_name {
-            "payload" => {
-                let value = field_to_string(field).await?;
-                payload = Some(value);
-            }
-            "file" => {
-                let file = field_to_file(field, &crate::CONFIG.static_dir).await?;
-                files.push(file);
+    let read = async {
+        while let Ok(Some(field)) = mp.try_next().await {
+            let disposition = field.content_disposition().ok_or(MultipartError::Decode)?;
+            let field_name = disposition.get_name().ok_or(MultipartError::Decode)?;
+            match field_name {
+                "payload" => {
+                    let value = field_to_string(field).await?;
+                    payload = Some(value);
+                }
+                "file" if files.is_empty() => {
+                    let file = field_to_file(field, &crate::CONFIG.static_dir, board).await?;
+                    files.push(file);
+                }
+                _ => {}
             }
-            _ => {}
+        }
+
+        let payload = payload.ok_or(MultipartError::BadRequest)?;
+        serde_json::from_str::<T>(&payload).map_err(MultipartError::InvalidField)
+    };
+
+    let read = read.await;
+    match read {
+        Ok(deserialized) => Ok((deserialized, files)),
+        Err(err) => {
+            discard(files).await;
+            Err(err)
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let payload = payload.ok_or(MultipartError::BadRequest)?;
-    let deserialized: T = serde_json::from_str(&payload)
-        .map_err(|err| MultipartError::InvalidField(err.to_string()))?;
+    #[test]
+    fn media_is_sniffed_from_the_bytes() {
+        assert_eq!(
+            sniff_media(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(
+            sniff_media(b"\xff\xd8\xff\xe0\0\x10JFIF\0"),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_media(b"GIF89a\x01\0\x01\0"), Some("image/gif"));
+        assert_eq!(sniff_media(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+    }
 
-    Ok((deserialized, files))
+    #[test]
+    fn anything_else_is_not_media() {
+        assert_eq!(sniff_media(b"<html><script>"), None);
+        assert_eq!(sniff_media(b""), None);
+        // a bmp is an image, just not one of ours
+        assert_eq!(sniff_media(b"BM\x36\0\0\0\0\0\0\0\x36\0"), None);
+    }
 }
@@ -0,0 +1,349 @@
+use super::sse_thread::{
+    BoardChange, BoardUpdate, Broadcaster, Channel, Event, ReplyNotice, WatchChange, WatchUpdate,
+};
+use crate::db::model::{
+    board_channel, channel_board, Board, Captcha, EventKind, Post, SpentChallenge, StoredEvent,
+    Thread, Watch, BOARDS_CHANNEL,
+};
+use actix_web::web::Data;
+use colored::Colorize;
+use futures::StreamExt;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::{delay_for, interval_at, Instant};
+
+// every instance LISTENs on a channel per board and fans the events out to its own subscribers
+// notifications only carry event ids, the events themselves are read back from the table
+// so nothing published while the connection was down gets lost
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// by the time anything is published the change it's about has committed, a failure
+// only costs live clients the update so it's logged instead of failing the request
+pub async fn publish(pool: &PgPool, board: &str, thread: i32, kind: EventKind, post: Option<i64>) {
+    if let Err(err) = StoredEvent::publish(pool, board, thread, kind, post).await {
+        eprintln!(
+            "{}: Couldn't publish an event for thread {}: {}",
+            "Warning".yellow(),
+            thread,
+            err
+        );
+    }
+}
+
+// threads past the limit get archived on the next post if this fails
+pub async fn update_locks(pool: &PgPool, board: &str) {
+    if let Err(err) = Board::update_locks(pool, board).await {
+        eprintln!(
+            "{}: Couldn't archive old threads on /{}/: {}",
+            "Warning".yellow(),
+            board,
+            err
+        );
+    }
+}
+
+// other instances start listening on the board when they restart if this fails
+pub async fn announce_board(pool: &PgPool, board: &str) {
+    if let Err(err) = StoredEvent::announce_board(pool, board).await {
+        eprintln!(
+            "{}: Couldn't announce /{}/: {}",
+            "Warning".yellow(),
+            board,
+            err
+        );
+    }
+}
+
+pub fn spawn_listener(pool: PgPool, brd: Data<Broadcaster>) {
+    let cleanup = pool.clone();
+    actix_rt::spawn(async move {
+        let mut task = interval_at(Instant::now(), Duration::from_secs(600));
+        while let Some(_) = task.next().await {
+            if let Err(err) = StoredEvent::delete_old(&cleanup).await {
+                eprintln!(
+                    "{}: Couldn't delete old events: {}",
+                    "Warning".yellow(),
+                    err
+                );
+            }
+            if let Err(err) = Watch::delete_stale(&cleanup).await {
+                eprintln!(
+                    "{}: Couldn't delete stale watches: {}",
+                    "Warning".yellow(),
+                    err
+                );
+            }
+            if let Err(err) = Captcha::delete_expired(&cleanup).await {
+                eprintln!(
+                    "{}: Couldn't delete expired captchas: {}",
+                    "Warning".yellow(),
+                    err
+                );
+            }
+            if let Err(err) = SpentChallenge::delete_expired(&cleanup).await {
+                eprintln!(
+                    "{}: Couldn't delete expired challenges: {}",
+                    "Warning".yellow(),
+                    err
+                );
+            }
+        }
+    });
+
+    actix_rt::spawn(async move {
+        // per board, everything up to and including the id has been dispatched
+        let mut last_dispatched = loop {
+            match StoredEvent::last_ids(&pool).await {
+                Ok(ids) => break ids,
+                Err(err) => {
+                    eprintln!(
+                        "{}: Couldn't read the last event: {}",
+                        "Warning".yellow(),
+                        err
+                    );
+                    delay_for(RECONNECT_DELAY).await;
+                }
+            }
+        };
+        loop {
+            if let Err(err) = listen(&pool, &brd, &mut last_dispatched).await {
+                eprintln!(
+                    "{}: Event listener disconnected: {}",
+                    "Warning".yellow(),
+                    err
+                );
+            }
+            delay_for(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+// boards that had no events yet start from the beginning
+async fn listen(
+    pool: &PgPool,
+    brd: &Broadcaster,
+    last_dispatched: &mut HashMap<String, i64>,
+) -> sqlx::Result<()> {
+    let mut listener = PgListener::connect(&crate::CONFIG.db_url).await?;
+    listener.listen(BOARDS_CHANNEL).await?;
+    let boards = Board::fetch_all(pool).await?;
+    for board in &boards {
+        listener.listen(&board_channel(&board.code)).await?;
+    }
+
+    // whatever was published while we weren't listening
+    for board in boards {
+        let last = last_dispatched.entry(board.code.clone()).or_insert(0);
+        dispatch(pool, brd, &board.code, last).await?;
+    }
+    loop {
+        let notification = listener.recv().await?;
+        let board = if notification.channel() == BOARDS_CHANNEL {
+            listener
+                .listen(&board_channel(notification.payload()))
+                .await?;
+            notification.payload()
+        } else {
+            match channel_board(notification.channel()) {
+                Some(board) => board,
+                None => continue,
+            }
+        };
+        let last = last_dispatched.entry(board.to_owned()).or_insert(0);
+        dispatch(pool, brd, board, last).await?;
+    }
+}
+
+// publishing takes a lock per board, so a board's events commit in id order and once
+// an id has been seen nothing below it can show up on that board anymore,
+// see StoredEvent::publish
+async fn dispatch(
+    pool: &PgPool,
+    brd: &Broadcaster,
+    board: &str,
+    last_dispatched: &mut i64,
+) -> sqlx::Result<()> {
+    for event in StoredEvent::fetch_after(pool, board, *last_dispatched).await? {
+        let thread = Channel::Thread(event.thread);
+        match (event.kind(), event.post) {
+            (Some(EventKind::Post), Some(post)) => {
+                if let Some(post) = Post::fetch(pool, post).await? {
+                    brd.send(&thread, Event::Post(&post));
+                    if let Some(change) = post_change(pool, &event, &post).await? {
+                        send_board(brd, &event, change, Some(&post));
+                    }
+                    send_personal(pool, brd, &post).await?;
+                }
+            }
+            (Some(EventKind::Delete), Some(post)) => {
+                brd.send(&thread, Event::Delete(post));
+                let change = counts_change(pool, event.thread).await?;
+                if let BoardChange::Delete = change {
+                    send_watchers(pool, brd, event.thread, WatchChange::Removed).await?;
+                }
+                send_board(brd, &event, change, None);
+            }
+            (Some(EventKind::Edit), Some(post)) => {
+                if let Some(post) = Post::fetch(pool, post).await? {
+                    brd.send(&thread, Event::Edit(&post));
+                }
+            }
+            (Some(EventKind::RemoveImage), Some(post)) => {
+                if let Some(post) = Post::fetch(pool, post).await? {
+                    brd.send(&thread, Event::RemoveImage(&post));
+                    let change = counts_change(pool, event.thread).await?;
+                    send_board(brd, &event, change, Some(&post));
+                }
+            }
+            (Some(EventKind::BanMessage), Some(post)) => {
+                if let Some(post) = Post::fetch(pool, post).await? {
+                    brd.send(&thread, Event::BanMessage(&post));
+                }
+            }
+            (Some(EventKind::Lock), _) => {
+                if let Some(fetched) = Thread::fetch(pool, event.thread).await? {
+                    brd.send(&thread, Event::Lock(fetched.locked));
+                    let change = BoardChange::Lock {
+                        locked: fetched.locked,
+                    };
+                    send_board(brd, &event, change, None);
+                }
+            }
+            (Some(EventKind::Sticky), _) => {
+                if let Some(fetched) = Thread::fetch(pool, event.thread).await? {
+                    brd.send(&thread, Event::Sticky(fetched.sticky));
+                    let change = BoardChange::Sticky {
+                        sticky: fetched.sticky,
+                    };
+                    send_board(brd, &event, change, None);
+                }
+            }
+            (Some(EventKind::Archive), _) => {
+                brd.send(&thread, Event::Archive);
+                send_board(brd, &event, BoardChange::Archive, None);
+                send_watchers(pool, brd, event.thread, WatchChange::Removed).await?;
+            }
+            // the event is on the old board, the thread already points at the new one
+            (Some(EventKind::Move), _) => {
+                if let Some(fetched) = Thread::fetch(pool, event.thread).await? {
+                    brd.send(&thread, Event::Move(&fetched.board));
+                    let moved = WatchChange::Moved {
+                        board: fetched.board.clone(),
+                    };
+                    send_watchers(pool, brd, fetched.id, moved).await?;
+                    let change = BoardChange::Moved {
+                        to: fetched.board.clone(),
+                    };
+                    send_board(brd, &event, change, None);
+                    let arrived = BoardUpdate {
+                        board: fetched.board.clone(),
+                        thread: fetched.id,
+                        change: BoardChange::NewThread {
+                            title: fetched.title,
+                        },
+                    };
+                    brd.send(&Channel::Board(fetched.board), Event::Board(&arrived, None));
+                }
+            }
+            _ => {}
+        }
+        *last_dispatched = event.id;
+    }
+    Ok(())
+}
+
+// the thread's counts after something was taken out of it
+async fn counts_change(pool: &PgPool, thread_id: i32) -> sqlx::Result<BoardChange> {
+    let counts = match Thread::reply_counts(pool, thread_id).await? {
+        Some(counts) => counts,
+        // that was the opening post and the thread went with it
+        None => return Ok(BoardChange::Delete),
+    };
+    Ok(match Thread::fetch(pool, thread_id).await? {
+        Some(fetched) => BoardChange::Replies {
+            replies: counts.replies,
+            images: counts.images,
+            last_updated: fetched.last_updated.timestamp(),
+        },
+        None => BoardChange::Delete,
+    })
+}
+
+// a new thread if it's the opening post, new counts otherwise
+async fn post_change(
+    pool: &PgPool,
+    event: &StoredEvent,
+    post: &Post,
+) -> sqlx::Result<Option<BoardChange>> {
+    let (thread, counts) = match (
+        Thread::fetch(pool, event.thread).await?,
+        Thread::reply_counts(pool, event.thread).await?,
+    ) {
+        (Some(thread), Some(counts)) => (thread, counts),
+        _ => return Ok(None),
+    };
+    if counts.opening == post.id {
+        return Ok(Some(BoardChange::NewThread {
+            title: thread.title,
+        }));
+    }
+    Ok(Some(BoardChange::Replies {
+        replies: counts.replies,
+        images: counts.images,
+        last_updated: thread.last_updated.timestamp(),
+    }))
+}
+
+// replies and watch list updates, approving a held post publishes it again
+// and that's when they go out
+async fn send_personal(pool: &PgPool, brd: &Broadcaster, post: &Post) -> sqlx::Result<()> {
+    let quoted = post.quoted_authors(pool).await?;
+    for watcher in Watch::watchers_of(pool, post).await? {
+        let update = WatchUpdate {
+            thread: post.thread,
+            change: WatchChange::NewPost {
+                you: quoted.iter().any(|(identity, _)| *identity == watcher),
+            },
+        };
+        brd.send(&Channel::Me(watcher), Event::Watch(&update, Some(post)));
+    }
+    for (identity, quoted) in quoted {
+        let notice = ReplyNotice {
+            board: post.board.clone(),
+            thread: post.thread,
+            number: post.number,
+            quoted,
+        };
+        brd.send(&Channel::Me(identity), Event::Reply(&notice, post));
+    }
+    Ok(())
+}
+
+async fn send_watchers(
+    pool: &PgPool,
+    brd: &Broadcaster,
+    thread: i32,
+    change: WatchChange,
+) -> sqlx::Result<()> {
+    let update = WatchUpdate { thread, change };
+    for watcher in Watch::watchers(pool, thread).await? {
+        brd.send(&Channel::Me(watcher), Event::Watch(&update, None));
+    }
+    Ok(())
+}
+
+fn send_board(brd: &Broadcaster, event: &StoredEvent, change: BoardChange, post: Option<&Post>) {
+    let update = BoardUpdate {
+        board: event.board.clone(),
+        thread: event.thread,
+        change,
+    };
+    brd.send(
+        &Channel::Board(event.board.clone()),
+        Event::Board(&update, post),
+    );
+    brd.send(&Channel::Overboard, Event::Board(&update, post));
+}
@@ -0,0 +1,403 @@
+use actix_web::{web::Data, HttpRequest};
+use futures::StreamExt;
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{interval_at, Instant};
+
+// hashcash-style challenges: the client has to find a `solution` such that
+// sha256(challenge + ":" + solution) starts with `difficulty` zero bits
+//
+// challenges are stateless, everything the server needs to check one is in the
+// token itself and signed with the private key:
+// "{nonce}.{board}.{expires}.{difficulty}.{signature}"
+// the signature also covers the identity, so a challenge can't be handed to someone else
+//
+// forms send the solution in a header as "{challenge}:{solution}", the same string that's
+// hashed, so it can be checked before the upload is read
+
+const MAX_EXTRA_DIFFICULTY: u32 = 8;
+const SOLUTION_HEADER: &str = "x-pow-solution";
+
+#[derive(Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+    pub expires: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Solution {
+    pub challenge: String,
+    pub solution: String,
+}
+
+impl Solution {
+    // None if the request didn't come with one
+    pub fn from_request(req: &HttpRequest) -> Result<Option<Self>, PowError> {
+        let header = match req.headers().get(SOLUTION_HEADER) {
+            Some(header) => header.to_str().map_err(|_| PowError::Malformed)?,
+            None => return Ok(None),
+        };
+        let split = header.find(':').ok_or(PowError::Malformed)?;
+        Ok(Some(Solution {
+            challenge: header[..split].to_owned(),
+            solution: header[split + 1..].to_owned(),
+        }))
+    }
+}
+
+// what a verified solution vouches for, it still has to be spent, see preconditions::take_proof
+pub struct Proof {
+    pub challenge: String,
+    pub expires: u64,
+    pub board: String,
+    pub difficulty: u32,
+}
+
+#[derive(Debug)]
+pub enum PowError {
+    Malformed,
+    Forged,
+    Expired,
+    Reused,
+    Unsolved,
+    WrongBoard,
+}
+impl std::fmt::Display for PowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match self {
+            Self::Malformed => "malformed challenge",
+            Self::Forged => "challenge wasn't issued to you",
+            Self::Expired => "challenge expired",
+            Self::Reused => "challenge was already used",
+            Self::Unsolved => "solution doesn't match the difficulty",
+            Self::WrongBoard => "challenge is for another board",
+        };
+        write!(f, "{}", message)
+    }
+}
+impl std::error::Error for PowError {}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("timey wimey stuff in pow::now")
+        .as_secs()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn sign(identity: &str, unsigned: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(crate::CONFIG.private_key.as_bytes())
+        .expect("hmac accepts keys of any size");
+    mac.update(identity.as_bytes());
+    mac.update(b"|");
+    mac.update(unsigned.as_bytes());
+    mac
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+pub struct PowGuard {
+    // post timestamps from the last minute, per board
+    recent_posts: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl PowGuard {
+    pub fn create() -> Data<PowGuard> {
+        let me = Data::new(PowGuard {
+            recent_posts: Mutex::new(HashMap::new()),
+        });
+        Self::spawn_cleanup(me.clone());
+        me
+    }
+
+    fn spawn_cleanup(me: Data<PowGuard>) {
+        actix_rt::spawn(async move {
+            let mut task = interval_at(Instant::now(), Duration::from_secs(60));
+            while let Some(_) = task.next().await {
+                let cutoff = Instant::now() - Duration::from_secs(60);
+                me.recent_posts.lock().unwrap().retain(|_, posts| {
+                    while posts.front().map_or(false, |posted| *posted < cutoff) {
+                        posts.pop_front();
+                    }
+                    !posts.is_empty()
+                });
+            }
+        })
+    }
+
+    pub fn record_post(&self, board: &str) {
+        self.recent_posts
+            .lock()
+            .unwrap()
+            .entry(board.to_owned())
+            .or_insert_with(VecDeque::new)
+            .push_back(Instant::now());
+    }
+
+    // one extra bit for every doubling of the posting rate above the threshold
+    fn difficulty(&self, board: &str, base: u32) -> u32 {
+        let cutoff = Instant::now() - Duration::from_secs(60);
+        let rate = self
+            .recent_posts
+            .lock()
+            .unwrap()
+            .get(board)
+            .map_or(0, |posts| {
+                posts.iter().filter(|posted| **posted >= cutoff).count()
+            });
+        let threshold = crate::CONFIG.pow_spike_threshold.max(1) as usize;
+        if rate < threshold {
+            return base;
+        }
+        let extra = (rate / threshold) as f64;
+        base + (extra.log2() as u32 + 1).min(MAX_EXTRA_DIFFICULTY)
+    }
+
+    pub fn issue(&self, board: &str, identity: &str, base: u32) -> Challenge {
+        let nonce: [u8; 16] = rand::thread_rng().gen();
+        let difficulty = self.difficulty(board, base);
+        let expires = now() + crate::CONFIG.pow_ttl;
+        let unsigned = format!("{}.{}.{}.{}", to_hex(&nonce), board, expires, difficulty);
+        let signature = to_hex(&sign(identity, &unsigned).finalize().into_bytes());
+
+        Challenge {
+            challenge: format!("{}.{}", unsigned, signature),
+            difficulty,
+            expires,
+        }
+    }
+
+    // the solution a form sent along, `board` when it's known without a lookup
+    pub fn verify_request(
+        &self,
+        req: &HttpRequest,
+        identity: &str,
+        board: Option<&str>,
+    ) -> Result<Option<Proof>, PowError> {
+        let solution = match Solution::from_request(req)? {
+            Some(solution) => solution,
+            None => return Ok(None),
+        };
+        let proof = self.verify(&solution, identity)?;
+        match board {
+            Some(board) if board != proof.board => Err(PowError::WrongBoard),
+            _ => Ok(Some(proof)),
+        }
+    }
+
+    // only checks the token and the solution, whether the proof is good enough
+    // for a particular board and whether it was spent already is up to the caller
+    pub fn verify(&self, solution: &Solution, identity: &str) -> Result<Proof, PowError> {
+        let token = &solution.challenge;
+        let split = token.rfind('.').ok_or(PowError::Malformed)?;
+        let (unsigned, signature) = (&token[..split], &token[split + 1..]);
+
+        let fields: Vec<&str> = unsigned.split('.').collect();
+        if fields.len() != 4 {
+            return Err(PowError::Malformed);
+        }
+        let expires: u64 = fields[2].parse().map_err(|_| PowError::Malformed)?;
+        let difficulty: u32 = fields[3].parse().map_err(|_| PowError::Malformed)?;
+
+        let signature = from_hex(signature).ok_or(PowError::Malformed)?;
+        sign(identity, unsigned)
+            .verify(&signature)
+            .map_err(|_| PowError::Forged)?;
+        if expires < now() {
+            return Err(PowError::Expired);
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(token.as_bytes());
+        hasher.update(b":");
+        hasher.update(solution.solution.as_bytes());
+        if leading_zero_bits(&hasher.finalize()) < difficulty {
+            return Err(PowError::Unsolved);
+        }
+
+        Ok(Proof {
+            challenge: token.to_owned(),
+            expires,
+            board: fields[1].to_owned(),
+            difficulty,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn guard() -> PowGuard {
+        PowGuard {
+            recent_posts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn solve(challenge: &Challenge) -> Solution {
+        let solution = (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| {
+                let mut hasher = Sha256::new();
+                hasher.update(challenge.challenge.as_bytes());
+                hasher.update(b":");
+                hasher.update(solution.as_bytes());
+                leading_zero_bits(&hasher.finalize()) >= challenge.difficulty
+            })
+            .unwrap();
+        Solution {
+            challenge: challenge.challenge.clone(),
+            solution,
+        }
+    }
+
+    fn signed(identity: &str, unsigned: &str) -> String {
+        let signature = to_hex(&sign(identity, unsigned).finalize().into_bytes());
+        format!("{}.{}", unsigned, signature)
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(from_hex(&to_hex(&[0, 15, 255])), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+
+    #[test]
+    fn solved_challenge_is_accepted() {
+        let guard = guard();
+        let solution = solve(&guard.issue("b", "someone", 8));
+        let proof = guard.verify(&solution, "someone").unwrap();
+        assert_eq!(proof.board, "b");
+        assert_eq!(proof.challenge, solution.challenge);
+        assert!(proof.difficulty >= 8);
+    }
+
+    #[test]
+    fn forms_send_the_solution_in_a_header() {
+        let guard = guard();
+        let request = |solution: Solution| {
+            let header = format!("{}:{}", solution.challenge, solution.solution);
+            TestRequest::default()
+                .header(SOLUTION_HEADER, header)
+                .to_http_request()
+        };
+        let req = request(solve(&guard.issue("b", "someone", 4)));
+        let proof = guard.verify_request(&req, "someone", Some("b")).unwrap();
+        assert_eq!(proof.map(|proof| proof.board), Some("b".to_owned()));
+        let req = request(solve(&guard.issue("b", "someone", 4)));
+        assert!(matches!(
+            guard.verify_request(&req, "someone", Some("g")),
+            Err(PowError::WrongBoard)
+        ));
+
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(
+            guard.verify_request(&req, "someone", Some("b")),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn challenge_is_tied_to_the_identity() {
+        let guard = guard();
+        let solution = solve(&guard.issue("b", "someone", 4));
+        assert!(matches!(
+            guard.verify(&solution, "someone else"),
+            Err(PowError::Forged)
+        ));
+    }
+
+    #[test]
+    fn unsolved_challenge_is_rejected() {
+        let guard = guard();
+        let challenge = guard.issue("b", "someone", 64);
+        let solution = Solution {
+            challenge: challenge.challenge,
+            solution: "0".to_owned(),
+        };
+        assert!(matches!(
+            guard.verify(&solution, "someone"),
+            Err(PowError::Unsolved)
+        ));
+    }
+
+    #[test]
+    fn expired_challenge_is_rejected() {
+        let token = signed("someone", &format!("00.b.{}.0", now() - 1));
+        let solution = Solution {
+            challenge: token,
+            solution: "0".to_owned(),
+        };
+        assert!(matches!(
+            guard().verify(&solution, "someone"),
+            Err(PowError::Expired)
+        ));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let expires = now() + 60;
+        let tokens = vec![
+            "".to_owned(),
+            "nonce".to_owned(),
+            signed("someone", &format!("00.b.{}", expires)),
+            signed("someone", &format!("00.b.{}.0.extra", expires)),
+            signed("someone", "00.b.soon.0"),
+            signed("someone", &format!("00.b.{}.lots", expires)),
+            format!("00.b.{}.0.nothex", expires),
+        ];
+        for token in tokens {
+            let solution = Solution {
+                challenge: token.clone(),
+                solution: "0".to_owned(),
+            };
+            assert!(
+                matches!(
+                    guard().verify(&solution, "someone"),
+                    Err(PowError::Malformed)
+                ),
+                "{}",
+                token
+            );
+        }
+    }
+}
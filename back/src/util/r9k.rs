@@ -0,0 +1,44 @@
+use sha2::{Digest, Sha256};
+
+// "Hello, World!!" and "hello world" are the same message as far as r9k is concerned
+pub fn normalize(message: &str) -> String {
+    message
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// None for messages with nothing left after normalizing, like image-only posts
+pub fn message_hash(message: &str) -> Option<Vec<u8>> {
+    let normalized = normalize(message);
+    if normalized.is_empty() {
+        return None;
+    }
+    Some(Sha256::digest(normalized.as_bytes()).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_punctuation_and_spacing_dont_count() {
+        assert_eq!(normalize("Hello, World!!"), "helloworld");
+        assert_eq!(normalize("  hello\n\tworld "), "helloworld");
+        assert_eq!(message_hash("Hello, World!!"), message_hash("hello world"));
+    }
+
+    #[test]
+    fn letters_and_digits_do() {
+        assert_eq!(normalize("ÄPFEL 42"), "äpfel42");
+        assert_ne!(message_hash("hello world"), message_hash("hello world 2"));
+    }
+
+    #[test]
+    fn nothing_left_means_no_hash() {
+        assert_eq!(message_hash(""), None);
+        assert_eq!(message_hash(">>12 ... !!"), message_hash("12"));
+        assert_eq!(message_hash("?! ..."), None);
+    }
+}
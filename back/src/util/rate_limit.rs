@@ -0,0 +1,322 @@
+use crate::db::model::Board;
+use actix_web::web::Data;
+use colored::Colorize;
+use futures::StreamExt;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::{interval_at, Instant};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    Thread,
+    Reply,
+    ImageReply,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Thread => "thread",
+            Self::Reply => "reply",
+            Self::ImageReply => "image_reply",
+        }
+    }
+
+    // boards can override the server-wide cooldowns
+    pub fn cooldown(&self, board: &Board) -> Duration {
+        let config = &crate::CONFIG;
+        let (board, default) = match self {
+            Self::Thread => (board.thread_cooldown, config.thread_cooldown),
+            Self::Reply => (board.reply_cooldown, config.reply_cooldown),
+            Self::ImageReply => (board.image_reply_cooldown, config.image_reply_cooldown),
+        };
+        Duration::from_secs(board.map_or(default, |seconds| seconds.max(0) as u64))
+    }
+}
+
+fn capacity() -> f64 {
+    crate::CONFIG.rate_limit_burst.max(1) as f64
+}
+
+// how long until a bucket with `tokens` tokens gets a whole one
+fn retry_after(tokens: f64, cooldown: Duration) -> Duration {
+    Duration::from_secs_f64((1.0 - tokens).max(0.0) * cooldown.as_secs_f64())
+}
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    Limited(Duration),
+    Internal(sqlx::Error),
+}
+impl From<sqlx::Error> for RateLimitError {
+    fn from(error: sqlx::Error) -> Self {
+        Self::Internal(error)
+    }
+}
+
+// buckets are shared between boards, they refill at the cooldown of wherever they were last used
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    cooldown: Duration,
+}
+
+impl Bucket {
+    fn refilled(&self, cooldown: Duration, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed / cooldown.as_secs_f64()).min(capacity())
+    }
+}
+
+struct StoredTokens {
+    tokens: f64,
+}
+
+// a token bucket per (action, key), every action takes a token and buckets refill
+// at one token per cooldown
+pub enum RateLimiter {
+    Memory(Mutex<HashMap<(Action, String), Bucket>>),
+    Postgres(PgPool),
+}
+
+impl RateLimiter {
+    pub fn create(pool: PgPool) -> Data<RateLimiter> {
+        let me = match crate::CONFIG.rate_limit_store.as_str() {
+            "postgres" => Data::new(RateLimiter::Postgres(pool)),
+            store => {
+                if store != "memory" {
+                    eprintln!(
+                        "{}: Unknown rate limit store \"{}\", using \"memory\"",
+                        "Warning".yellow(),
+                        store
+                    );
+                }
+                Data::new(RateLimiter::Memory(Mutex::new(HashMap::new())))
+            }
+        };
+        Self::spawn_cleanup(me.clone());
+        me
+    }
+
+    // full buckets are the same as missing ones, drop them once a minute so the store doesn't grow forever
+    fn spawn_cleanup(me: Data<RateLimiter>) {
+        actix_rt::spawn(async move {
+            let mut task = interval_at(Instant::now(), Duration::from_secs(60));
+            while let Some(_) = task.next().await {
+                match me.as_ref() {
+                    RateLimiter::Memory(buckets) => {
+                        let now = Instant::now();
+                        buckets
+                            .lock()
+                            .unwrap()
+                            .retain(|_, bucket| bucket.refilled(bucket.cooldown, now) < capacity());
+                    }
+                    RateLimiter::Postgres(pool) => {
+                        if let Err(err) = delete_full(pool).await {
+                            eprintln!(
+                                "{}: Couldn't delete full rate limit buckets: {}",
+                                "Warning".yellow(),
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    // takes a token for every key, or none of them if any of the buckets is empty
+    pub async fn check(
+        &self,
+        action: Action,
+        board: &Board,
+        keys: &[&str],
+    ) -> Result<(), RateLimitError> {
+        let cooldown = action.cooldown(board);
+        if cooldown.as_secs() == 0 {
+            return Ok(());
+        }
+        match self {
+            Self::Memory(buckets) => {
+                let now = Instant::now();
+                let mut buckets = buckets.lock().unwrap();
+                let mut tokens = Vec::with_capacity(keys.len());
+
+                for key in keys {
+                    let available = buckets
+                        .get(&(action, key.to_string()))
+                        .map(|bucket| bucket.refilled(cooldown, now))
+                        .unwrap_or_else(capacity);
+                    tokens.push(available);
+                }
+                check_tokens(&tokens, cooldown)?;
+
+                for (key, available) in keys.iter().zip(tokens) {
+                    buckets.insert(
+                        (action, key.to_string()),
+                        Bucket {
+                            tokens: available - 1.0,
+                            updated: now,
+                            cooldown,
+                        },
+                    );
+                }
+                Ok(())
+            }
+            Self::Postgres(pool) => {
+                let mut tx = pool.begin().await?;
+                let mut tokens = Vec::with_capacity(keys.len());
+
+                for key in keys {
+                    sqlx::query!(
+                        "INSERT INTO rate_limits (key, action, tokens) \
+                        VALUES ($1, $2, $3) \
+                        ON CONFLICT (key, action) DO NOTHING",
+                        *key,
+                        action.name(),
+                        capacity()
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    // the row lock keeps other instances from spending the same token
+                    let stored = sqlx::query_as!(
+                        StoredTokens,
+                        "SELECT LEAST($3, tokens + EXTRACT(EPOCH FROM now() - updated) / $4)::float8 \
+                            AS tokens \
+                        FROM rate_limits \
+                        WHERE key = $1 AND action = $2 \
+                        FOR UPDATE",
+                        *key,
+                        action.name(),
+                        capacity(),
+                        cooldown.as_secs_f64()
+                    )
+                    .fetch_one(&mut tx)
+                    .await?;
+                    tokens.push(stored.tokens);
+                }
+                check_tokens(&tokens, cooldown)?;
+
+                for (key, available) in keys.iter().zip(tokens) {
+                    sqlx::query!(
+                        "UPDATE rate_limits SET tokens = $3, updated = now(), cooldown = $4 \
+                        WHERE key = $1 AND action = $2",
+                        *key,
+                        action.name(),
+                        available - 1.0,
+                        cooldown.as_secs_f64()
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                tx.commit().await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+// the same as the memory store's cleanup, buckets from before cooldowns were stored go as well
+async fn delete_full(pool: &PgPool) -> sqlx::Result<()> {
+    sqlx::query!(
+        "DELETE FROM rate_limits \
+        WHERE cooldown <= 0 \
+        OR tokens + EXTRACT(EPOCH FROM now() - updated) / NULLIF(cooldown, 0) >= $1",
+        capacity()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+fn check_tokens(tokens: &[f64], cooldown: Duration) -> Result<(), RateLimitError> {
+    let wait = tokens
+        .iter()
+        .filter(|available| **available < 1.0)
+        .map(|available| retry_after(*available, cooldown))
+        .max();
+    match wait {
+        Some(wait) => Err(RateLimitError::Limited(wait)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(10);
+
+    fn empty_bucket(updated: Instant) -> Bucket {
+        Bucket {
+            tokens: 0.0,
+            updated,
+            cooldown: COOLDOWN,
+        }
+    }
+
+    fn assert_tokens(tokens: f64, expected: f64) {
+        assert!(
+            (tokens - expected).abs() < 1e-9,
+            "{} != {}",
+            tokens,
+            expected
+        );
+    }
+
+    #[test]
+    fn buckets_refill_a_token_per_cooldown() {
+        let start = Instant::now();
+        let bucket = empty_bucket(start);
+        assert_tokens(bucket.refilled(COOLDOWN, start), 0.0);
+        assert_tokens(bucket.refilled(COOLDOWN, start + COOLDOWN / 2), 0.5);
+        assert_tokens(bucket.refilled(COOLDOWN, start + COOLDOWN), 1.0);
+    }
+
+    #[test]
+    fn buckets_stop_refilling_when_full() {
+        let start = Instant::now();
+        let bucket = empty_bucket(start);
+        assert_tokens(
+            bucket.refilled(COOLDOWN, start + COOLDOWN * 100),
+            capacity(),
+        );
+    }
+
+    #[test]
+    fn waiting_is_until_the_emptiest_bucket_has_a_token() {
+        assert!(check_tokens(&[1.0, 1.5], COOLDOWN).is_ok());
+        match check_tokens(&[2.0, 0.5, 0.75], COOLDOWN) {
+            Err(RateLimitError::Limited(wait)) => assert_eq!(wait, COOLDOWN / 2),
+            _ => panic!("expected to be limited"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn memory_store_allows_a_burst_and_then_limits() {
+        let limiter = RateLimiter::Memory(Mutex::new(HashMap::new()));
+        let board = Board {
+            reply_cooldown: Some(COOLDOWN.as_secs() as i32),
+            ..Board::default()
+        };
+        for _ in 0..capacity() as usize {
+            assert!(limiter.check(Action::Reply, &board, &["a"]).await.is_ok());
+        }
+        assert!(matches!(
+            limiter.check(Action::Reply, &board, &["a"]).await,
+            Err(RateLimitError::Limited(_))
+        ));
+        // another key and another action have buckets of their own
+        assert!(limiter.check(Action::Reply, &board, &["b"]).await.is_ok());
+        let thread_board = Board {
+            thread_cooldown: Some(COOLDOWN.as_secs() as i32),
+            ..board
+        };
+        assert!(limiter
+            .check(Action::Thread, &thread_board, &["a"])
+            .await
+            .is_ok());
+    }
+}
@@ -1,119 +1,947 @@
-use crate::db::model::{Post, ThreadWithPosts};
+use super::notify;
+use crate::db::model::{Post, ThreadWithPosts, Viewer};
 use actix_web::web::{Bytes, Data};
 use actix_web::Error;
+use dashmap::DashMap;
+use futures::future::{select, Either};
 use futures::{Stream, StreamExt};
-use serde_json::json;
-use std::collections::HashMap;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::collections::{HashSet, VecDeque};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::Mutex;
-use tokio::time::{interval_at, Instant};
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio::time::{interval_at, timeout, Instant};
 
+// posts kept per thread for clients that reconnect with Last-Event-ID
+const HISTORY_SIZE: usize = 100;
+// per client, enough for the snapshot or replay plus a slow reader
+const CHANNEL_SIZE: usize = 100;
+// per thread, shared by all of its subscribers
+const TOPIC_CAPACITY: usize = 64;
+// how long a client with a full buffer gets to catch up before it's dropped
+const SLOW_CLIENT_GRACE: Duration = Duration::from_secs(5);
+// how long clients should wait before reconnecting when the server goes down, in ms
+const RECONNECT_RETRY: u64 = 5000;
+// how long a thread keeps its history after the last subscriber left, so a lone viewer
+// who reconnects doesn't have to go to the database
+const HISTORY_GRACE: Duration = Duration::from_secs(300);
+
+// what a topic is about, threads get everything, boards and the overboard get
+// compact updates that are enough to keep a catalog current
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Channel {
+    Thread(i32),
+    Board(String),
+    Overboard,
+    // replies to posts made by this identity and news about the threads it watches
+    Me(String),
+}
+
+impl Channel {
+    // how websocket clients name it, the identity is left out since it's the session cookie
+    pub fn describe(&self) -> Value {
+        match self {
+            Self::Thread(id) => json!({ "thread": id }),
+            Self::Board(code) => json!({ "board": code }),
+            Self::Overboard => json!("overboard"),
+            Self::Me(_) => json!("me"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BoardChange {
+    NewThread {
+        title: String,
+    },
+    // counts only include public posts, a reply that bumped the thread moves last_updated
+    Replies {
+        replies: i64,
+        images: i64,
+        last_updated: i64,
+    },
+    Lock {
+        locked: bool,
+    },
+    Sticky {
+        sticky: bool,
+    },
+    Archive,
+    // the new board gets a new_thread
+    Moved {
+        to: String,
+    },
+    Delete,
+}
+
+#[derive(Serialize)]
+pub struct BoardUpdate {
+    pub board: String,
+    pub thread: i32,
+    #[serde(flatten)]
+    pub change: BoardChange,
+}
+
+// someone quoted your posts, numbers are per board
+#[derive(Serialize)]
+pub struct ReplyNotice {
+    pub board: String,
+    pub thread: i32,
+    pub number: i64,
+    pub quoted: Vec<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WatchChange {
+    // `you` if it quotes one of your posts
+    NewPost { you: bool },
+    Moved { board: String },
+    // archived or deleted, the watch is gone too
+    Removed,
+}
+
+// for threads on the watch list, counts are kept by the client
+#[derive(Serialize)]
+pub struct WatchUpdate {
+    pub thread: i32,
+    #[serde(flatten)]
+    pub change: WatchChange,
+}
+
+// every event has a name and at most one json object as data, clients should ignore names
+// they don't know, posts look the same as everywhere else in the api
+//
+//   thread        thread with posts (snapshot, id: last post)
+//   post          post (id: post id)
+//   edit          post, the whole post as it is now
+//   delete        { "id": post id }
+//   remove_image  { "id": post id }
+//   ban_message   { "id": post id, "message": string }
+//   lock          { "locked": bool }
+//   sticky        { "sticky": bool }
+//   archive       no data, the thread is read-only from now on
+//   move          { "board": code of the new board }
+//   board         { "board", "thread", "kind", ... }, see BoardChange
+//   reply         { "board", "thread", "number", "quoted": [numbers] }
+//   watch         { "thread", "kind", ... }, see WatchChange
+//   ping          no data
+//   resync        no data, the client was dropped for falling behind and should refetch
+//   reconnect     { "retry": ms }, the server is going down, the stream ends after this
 #[derive(Clone)]
 pub enum Event<'a> {
-    Thread(&'a Option<ThreadWithPosts>),
+    Thread(&'a ThreadWithPosts),
     Post(&'a Post),
+    Edit(&'a Post),
+    // post id
+    Delete(i64),
+    RemoveImage(&'a Post),
+    BanMessage(&'a Post),
+    Lock(bool),
+    Sticky(bool),
+    Archive,
+    // board code
+    Move(&'a str),
+    // the post that caused it, if any, decides who gets to see it
+    Board(&'a BoardUpdate, Option<&'a Post>),
+    Reply(&'a ReplyNotice, &'a Post),
+    Watch(&'a WatchUpdate, Option<&'a Post>),
     Ping,
+    Resync,
+    // ms
+    Reconnect(u64),
 }
 
 impl<'a> Event<'a> {
-    pub fn to_message(self) -> Bytes {
-        let message = match self {
-            Self::Thread(thread) => format!(
-                "event: thread\ndata: {}\n\n",
-                serde_json::to_string(thread).unwrap()
-            ),
-            Self::Post(post) => format!(
-                "event: post\ndata: {}\n\n",
-                serde_json::to_string(post).unwrap()
-            ),
-            Self::Ping => "event: ping\n\n".to_owned(),
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Thread(_) => "thread",
+            Self::Post(_) => "post",
+            Self::Edit(_) => "edit",
+            Self::Delete(_) => "delete",
+            Self::RemoveImage(_) => "remove_image",
+            Self::BanMessage(_) => "ban_message",
+            Self::Lock(_) => "lock",
+            Self::Sticky(_) => "sticky",
+            Self::Archive => "archive",
+            Self::Move(_) => "move",
+            Self::Board(..) => "board",
+            Self::Reply(..) => "reply",
+            Self::Watch(..) => "watch",
+            Self::Ping => "ping",
+            Self::Resync => "resync",
+            Self::Reconnect(_) => "reconnect",
+        }
+    }
+
+    // event ids are post ids, a thread snapshot gets the id of its last post
+    // so the browser sends back the newest post it has seen when it reconnects
+    fn id(&self) -> Option<i64> {
+        match self {
+            Self::Thread(thread) => thread.last_post_id(),
+            Self::Post(post) => Some(post.id),
+            _ => None,
+        }
+    }
+
+    fn data(&self) -> Option<String> {
+        let data = match self {
+            Self::Thread(thread) => serde_json::to_string(thread).unwrap(),
+            Self::Post(post) | Self::Edit(post) => serde_json::to_string(post).unwrap(),
+            Self::Delete(id) => json!({ "id": id }).to_string(),
+            Self::RemoveImage(post) => json!({ "id": post.id }).to_string(),
+            Self::BanMessage(post) => json!({
+                "id": post.id,
+                "message": post.ban_message
+            })
+            .to_string(),
+            Self::Lock(locked) => json!({ "locked": locked }).to_string(),
+            Self::Sticky(sticky) => json!({ "sticky": sticky }).to_string(),
+            Self::Move(board) => json!({ "board": board }).to_string(),
+            Self::Board(update, _) => serde_json::to_string(update).unwrap(),
+            Self::Reply(notice, _) => serde_json::to_string(notice).unwrap(),
+            Self::Watch(update, _) => serde_json::to_string(update).unwrap(),
+            Self::Reconnect(retry) => json!({ "retry": retry }).to_string(),
+            Self::Archive | Self::Ping | Self::Resync => return None,
         };
+        Some(data)
+    }
+
+    // the post that decides who gets to see this
+    fn post(&self) -> Option<&'a Post> {
+        match *self {
+            Self::Post(post)
+            | Self::Edit(post)
+            | Self::RemoveImage(post)
+            | Self::BanMessage(post)
+            | Self::Board(_, Some(post))
+            | Self::Reply(_, post)
+            | Self::Watch(_, Some(post)) => Some(post),
+            _ => None,
+        }
+    }
+
+    // serialized once per broadcast, subscribers share the result
+    pub fn to_frame(self) -> Frame {
+        Frame {
+            event: self.name(),
+            id: self.id(),
+            retry: match self {
+                Self::Reconnect(retry) => Some(retry),
+                _ => None,
+            },
+            data: self.data().map(Arc::from),
+        }
+    }
+}
+
+// a serialized event, the same frame goes to every subscriber whatever the transport
+#[derive(Clone)]
+pub struct Frame {
+    event: &'static str,
+    id: Option<i64>,
+    // sse clients take this as their reconnection delay, in ms
+    retry: Option<u64>,
+    data: Option<Arc<str>>,
+}
+
+impl Frame {
+    pub fn to_sse(&self) -> Bytes {
+        let mut message = String::new();
+        if let Some(id) = self.id {
+            message.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            message.push_str(&format!("retry: {}\n", retry));
+        }
+        message.push_str(&format!("event: {}\n", self.event));
+        if let Some(data) = &self.data {
+            message.push_str(&format!("data: {}\n", data));
+        }
+        message.push('\n');
         Bytes::from(message)
     }
+
+    // the data is already json, it's spliced in rather than parsed and serialized again
+    pub fn to_json(&self, channel: &Channel) -> String {
+        format!(
+            "{{\"type\":\"event\",\"channel\":{},\"event\":\"{}\",\"id\":{},\"data\":{}}}",
+            channel.describe(),
+            self.event,
+            self.id.map_or("null".to_owned(), |id| id.to_string()),
+            self.data.as_deref().unwrap_or("null")
+        )
+    }
+}
+
+// serialized once and shared by everyone on the topic
+#[derive(Clone)]
+struct Message {
+    frame: Frame,
+    // held and shadow-banned posts only go out to their author and staff
+    post: Option<Arc<Post>>,
+    // subscribers are done after this one
+    last: bool,
+}
+
+impl Message {
+    fn new(event: Event, post: Option<Arc<Post>>) -> Self {
+        Message {
+            frame: event.to_frame(),
+            post,
+            last: false,
+        }
+    }
+
+    fn from_event(event: Event) -> Self {
+        let post = event.post().map(|post| Arc::new(post.clone()));
+        Self::new(event, post)
+    }
+
+    fn visible_to(&self, viewer: &Viewer) -> bool {
+        self.post
+            .as_ref()
+            .map_or(true, |post| post.visible_to(viewer))
+    }
 }
 
-type Subscriber = Sender<Bytes>;
+struct Topic {
+    tx: broadcast::Sender<Message>,
+    subscribers: usize,
+    // the last posts sent to a thread, in the order they went out, always empty for boards
+    history: VecDeque<Arc<Post>>,
+    // since the last subscriber left, threads are kept around for HISTORY_GRACE
+    idle_since: Option<Instant>,
+}
+
+impl Topic {
+    fn new() -> Self {
+        let (tx, _) = broadcast::channel(TOPIC_CAPACITY);
+        Topic {
+            tx,
+            subscribers: 0,
+            history: VecDeque::new(),
+            idle_since: None,
+        }
+    }
 
-#[derive(Clone, Debug)]
-pub struct Broadcaster(HashMap<i32, Vec<Subscriber>>);
+    fn expired(&self, now: Instant) -> bool {
+        self.idle_since
+            .map_or(false, |since| now.duration_since(since) >= HISTORY_GRACE)
+    }
+}
+
+#[derive(Debug)]
+pub enum SubscribeError {
+    // the instance's subscriber limit
+    Full,
+    TooManyForAddress,
+    ShuttingDown,
+}
+
+impl std::fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Full => write!(f, "Too many people are following live updates right now"),
+            Self::TooManyForAddress => write!(f, "Too many live updates open from your address"),
+            Self::ShuttingDown => write!(f, "The server is restarting"),
+        }
+    }
+}
+impl std::error::Error for SubscribeError {}
+
+// resolves on ctrl-c or SIGTERM, whichever comes first
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            select(
+                Box::pin(actix_rt::signal::ctrl_c()),
+                Box::pin(terminate.recv()),
+            )
+            .await;
+            return;
+        }
+    }
+    let _ = actix_rt::signal::ctrl_c().await;
+}
+
+// a broadcast channel per topic, only topics somebody is watching have one
+// the map is sharded, so posting to one thread never waits on subscribers of another
+pub struct Broadcaster {
+    topics: Arc<DashMap<Channel, Topic>>,
+    // subscribers per client address
+    addresses: Arc<DashMap<String, usize>>,
+    counters: Arc<Counters>,
+    closing: AtomicBool,
+}
+
+#[derive(Default)]
+struct Counters {
+    subscribers: AtomicUsize,
+    // waiting for room in their buffer right now
+    lagging: AtomicUsize,
+    // since startup
+    dropped: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub topics: usize,
+    pub subscribers: usize,
+    pub lagging: usize,
+    pub dropped: u64,
+}
 
 impl Broadcaster {
-    pub fn create() -> Data<Mutex<Broadcaster>> {
-        let me = Data::new(Mutex::new(Self::new()));
+    // events reach subscribers through postgres, see notify::spawn_listener
+    pub fn create(pool: PgPool) -> Data<Broadcaster> {
+        let me = Data::new(Broadcaster {
+            topics: Arc::new(DashMap::new()),
+            addresses: Arc::new(DashMap::new()),
+            counters: Arc::new(Counters::default()),
+            closing: AtomicBool::new(false),
+        });
         Self::spawn_ping(me.clone());
+        Self::spawn_shutdown(me.clone());
+        notify::spawn_listener(pool, me.clone());
         me
     }
 
-    fn new() -> Self {
-        Broadcaster(HashMap::new())
-    }
-    // spawn a cleanup task that pings every subscriber once a minute and removes the dead ones
-    fn spawn_ping(me: Data<Mutex<Broadcaster>>) {
+    // keeps idle connections from being closed by proxies, dead ones are noticed
+    // when writing to them fails and clean up after themselves
+    // threads nobody came back to in time are let go of here as well
+    fn spawn_ping(me: Data<Broadcaster>) {
         actix_rt::spawn(async move {
             let mut task = interval_at(Instant::now(), Duration::from_secs(60));
             while let Some(_) = task.next().await {
-                me.lock().await.remove_dead();
+                let ping = Message::new(Event::Ping, None);
+                for topic in me.topics.iter() {
+                    let _ = topic.tx.send(ping.clone());
+                }
+                let now = Instant::now();
+                me.topics.retain(|_, topic| !topic.expired(now));
+            }
+        })
+    }
+
+    // actix stops accepting connections on its own, but open streams would only be cut
+    // once the shutdown timeout runs out, this ends them with a hint to come back later
+    fn spawn_shutdown(me: Data<Broadcaster>) {
+        actix_rt::spawn(async move {
+            shutdown_signal().await;
+            me.closing.store(true, Ordering::SeqCst);
+            let reconnect = Message {
+                last: true,
+                ..Message::new(Event::Reconnect(RECONNECT_RETRY), None)
+            };
+            for topic in me.topics.iter() {
+                let _ = topic.tx.send(reconnect.clone());
             }
         })
     }
 
-    fn remove_dead(&mut self) {
-        let mut thread_subscribers = HashMap::with_capacity(self.0.capacity());
+    pub fn subscribers(&self, channel: &Channel) -> usize {
+        self.topics
+            .get(channel)
+            .map_or(0, |topic| topic.subscribers)
+    }
 
-        for (thread, subscribers) in self.0.iter() {
-            let mut live_subscribers = Vec::with_capacity(subscribers.capacity());
+    pub fn total_subscribers(&self) -> usize {
+        self.topics.iter().map(|topic| topic.subscribers).sum()
+    }
 
-            for subscriber in subscribers.iter() {
-                let mut subscriber = subscriber.clone();
-                let result = subscriber.try_send(Event::Ping.to_message());
-                if let Ok(_) = result {
-                    live_subscribers.push(subscriber);
-                }
+    pub fn stats(&self) -> Stats {
+        Stats {
+            topics: self.topics.len(),
+            subscribers: self.total_subscribers(),
+            lagging: self.counters.lagging.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    // takes a slot for the address, given back when the subscription is dropped
+    fn reserve(&self, address: &str) -> Result<(), SubscribeError> {
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(SubscribeError::ShuttingDown);
+        }
+        let total = self.counters.subscribers.fetch_add(1, Ordering::SeqCst);
+        if total >= crate::CONFIG.max_subscribers {
+            self.counters.subscribers.fetch_sub(1, Ordering::SeqCst);
+            return Err(SubscribeError::Full);
+        }
+        let mut count = self.addresses.entry(address.to_owned()).or_insert(0);
+        if *count >= crate::CONFIG.max_subscribers_per_ip {
+            self.counters.subscribers.fetch_sub(1, Ordering::SeqCst);
+            return Err(SubscribeError::TooManyForAddress);
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    // subscribes to the channel, `read` looks at the topic in the same step so nothing sent
+    // to it can slip in between, messages wait in the topic's channel until the subscription
+    // is started
+    fn join<T>(
+        &self,
+        channel: Channel,
+        address: &str,
+        read: impl FnOnce(&Topic) -> T,
+    ) -> Result<(Joined, T), SubscribeError> {
+        self.reserve(address)?;
+        let (updates, read) = {
+            let mut topic = self
+                .topics
+                .entry(channel.clone())
+                .or_insert_with(Topic::new);
+            topic.subscribers += 1;
+            topic.idle_since = None;
+            (topic.tx.subscribe(), read(&*topic))
+        };
+        let subscription = Subscription {
+            topics: self.topics.clone(),
+            channel,
+            cancel: None,
+            addresses: self.addresses.clone(),
+            address: address.to_owned(),
+            counters: self.counters.clone(),
+        };
+        Ok((
+            Joined {
+                updates,
+                subscription,
+            },
+            read,
+        ))
+    }
+
+    pub fn subscribe(
+        &self,
+        channel: Channel,
+        initial: Vec<Frame>,
+        viewer: Viewer,
+        address: &str,
+    ) -> Result<Client, SubscribeError> {
+        let (joined, _) = self.join(channel, address, |_| ())?;
+        let (rx, subscription) = joined.start(initial, HashSet::new(), viewer);
+        Ok(Client {
+            rx,
+            _subscription: subscription,
+        })
+    }
+
+    // for websockets, which read whatever goes out first themselves, see Joined::frames
+    pub fn join_channel(&self, channel: Channel, address: &str) -> Result<Joined, SubscribeError> {
+        let (joined, _) = self.join(channel, address, |_| ())?;
+        Ok(joined)
+    }
+
+    // the snapshot or replay for a thread is read after this, see Joined
+    // with a `last_event_id` it also comes with everything the viewer should've gotten
+    // after it, unless that event already fell out of the history and the database has
+    // to be asked instead
+    // goes by the order events were sent in rather than by id, approved posts keep
+    // their old ids but still come after whatever was sent before them
+    pub fn join_thread(
+        &self,
+        thread_id: i32,
+        last_event_id: Option<i64>,
+        viewer: &Viewer,
+        address: &str,
+    ) -> Result<(Joined, Option<Vec<Post>>), SubscribeError> {
+        self.join(Channel::Thread(thread_id), address, |topic| {
+            let last_event_id = last_event_id?;
+            let seen = topic
+                .history
+                .iter()
+                .position(|post| post.id == last_event_id)?;
+            Some(
+                topic
+                    .history
+                    .iter()
+                    .skip(seen + 1)
+                    .filter(|post| post.visible_to(viewer))
+                    .map(|post| (**post).clone())
+                    .collect(),
+            )
+        })
+    }
+
+    // channels nobody is watching are skipped entirely, reconnects to them go to the database
+    // threads somebody just left still keep their history, see HISTORY_GRACE
+    pub fn send(&self, channel: &Channel, event: Event) {
+        if let Some(mut topic) = self.topics.get_mut(channel) {
+            // only new posts are replayed to reconnecting clients, changes to them are
+            // made to the copy in the history so nobody gets the old version
+            let thread = matches!(channel, Channel::Thread(_));
+            let replayed = thread && matches!(event, Event::Post(_));
+            let changed = thread
+                && matches!(
+                    event,
+                    Event::Edit(_) | Event::RemoveImage(_) | Event::BanMessage(_)
+                );
+            // deleted posts aren't replayed at all
+            if let (true, Event::Delete(id)) = (thread, &event) {
+                topic.history.retain(|sent| sent.id != *id);
             }
-            if live_subscribers.len() > 0 {
-                thread_subscribers.insert(*thread, live_subscribers);
+            let message = Message::from_event(event);
+            match (replayed, changed, &message.post) {
+                (true, _, Some(post)) => {
+                    if topic.history.len() == HISTORY_SIZE {
+                        topic.history.pop_front();
+                    }
+                    topic.history.push_back(post.clone());
+                }
+                (_, true, Some(post)) => {
+                    for sent in topic.history.iter_mut().filter(|sent| sent.id == post.id) {
+                        *sent = post.clone();
+                    }
+                }
+                _ => {}
             }
+            // fails if every subscriber is gone already, their subscriptions clean up the topic
+            let _ = topic.tx.send(message);
         }
-        self.0 = thread_subscribers;
     }
+}
 
-    pub fn new_subscriber(&mut self, thread: Option<ThreadWithPosts>) -> Option<Client> {
-        let (tx, rx) = channel(100);
+// subscribed to a topic but not sending anything yet, whatever goes out in the meantime
+// waits in the topic's channel
+pub struct Joined {
+    updates: broadcast::Receiver<Message>,
+    subscription: Subscription,
+}
 
-        let res = tx.clone().try_send(Event::Thread(&thread).to_message());
+impl Joined {
+    // `initial` goes out before anything sent to the channel since joining, posts in
+    // `replayed` that were sent in the meantime as well only go out once
+    fn start(
+        mut self,
+        initial: Vec<Frame>,
+        replayed: HashSet<i64>,
+        viewer: Viewer,
+    ) -> (Receiver, Subscription) {
+        // sized so that everything fits
+        let (mut tx, rx) = mpsc::channel(CHANNEL_SIZE.max(initial.len() + 1));
+        for frame in initial {
+            let _ = tx.try_send(frame);
+        }
 
-        if let Err(_) = res {
-            return None;
+        let (cancel, cancelled) = oneshot::channel();
+        self.subscription.cancel = Some(cancel);
+        let dropped = Arc::new(AtomicBool::new(false));
+        actix_rt::spawn(forward(
+            self.updates,
+            tx,
+            viewer,
+            replayed,
+            cancelled,
+            self.subscription.counters.clone(),
+            dropped.clone(),
+        ));
+        (Receiver { rx, dropped }, self.subscription)
+    }
+
+    fn client(self, initial: Vec<Frame>, replayed: HashSet<i64>, viewer: Viewer) -> Client {
+        let (rx, subscription) = self.start(initial, replayed, viewer);
+        Client {
+            rx,
+            _subscription: subscription,
         }
-        if let Some(thread) = thread {
-            self.0.entry(thread.id).or_insert(Vec::new()).push(tx);
+    }
+
+    pub fn snapshot(self, thread: &ThreadWithPosts, viewer: Viewer) -> Client {
+        let replayed = thread.post_ids().collect();
+        self.client(vec![Event::Thread(thread).to_frame()], replayed, viewer)
+    }
+
+    // the same thing for websockets, where one connection follows many channels
+    // `replayed` works like it does for `snapshot`
+    pub fn frames(self, initial: Vec<Frame>, replayed: HashSet<i64>, viewer: Viewer) -> Frames {
+        let channel = self.subscription.channel.clone();
+        let (rx, subscription) = self.start(initial, replayed, viewer);
+        Frames {
+            channel,
+            rx,
+            ended: false,
+            _subscription: subscription,
+        }
+    }
+
+    // picks up a reconnecting client without sending the whole thread again
+    pub fn resume(self, missed: Vec<Post>, viewer: Viewer) -> Client {
+        let replayed = missed.iter().map(|post| post.id).collect();
+        let missed = missed
+            .iter()
+            .map(|post| Event::Post(post).to_frame())
+            .collect();
+        self.client(missed, replayed, viewer)
+    }
+}
+
+// moves messages from the topic's channel to a single client, filtered for its viewer
+// a client that can't keep up is dropped rather than quietly missing events
+async fn forward(
+    mut updates: broadcast::Receiver<Message>,
+    mut tx: mpsc::Sender<Frame>,
+    viewer: Viewer,
+    mut replayed: HashSet<i64>,
+    mut cancelled: oneshot::Receiver<()>,
+    counters: Arc<Counters>,
+    dropped: Arc<AtomicBool>,
+) {
+    loop {
+        let message = match select(Box::pin(updates.recv()), &mut cancelled).await {
+            Either::Left((Ok(message), _)) => message,
+            // whatever fell out of the topic's channel is gone for good
+            Either::Left((Err(RecvError::Lagged(_)), _)) => break,
+            Either::Left((Err(RecvError::Closed), _)) | Either::Right(_) => return,
+        };
+        if message.last {
+            let _ = timeout(SLOW_CLIENT_GRACE, tx.send(message.frame)).await;
+            return;
+        }
+        if !message.visible_to(&viewer) {
+            continue;
         }
+        // only post events have ids, the second time a post goes out it was approved
+        if let Some(id) = message.frame.id {
+            if replayed.remove(&id) {
+                continue;
+            }
+        }
+        match tx.try_send(message.frame) {
+            Ok(()) => {}
+            Err(TrySendError::Closed(_)) => return,
+            Err(TrySendError::Full(frame)) => {
+                counters.lagging.fetch_add(1, Ordering::Relaxed);
+                let sent = timeout(SLOW_CLIENT_GRACE, tx.send(frame)).await;
+                counters.lagging.fetch_sub(1, Ordering::Relaxed);
+                match sent {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => return,
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+    // the receiving end sends the resync once it has drained what's already buffered
+    counters.dropped.fetch_add(1, Ordering::Relaxed);
+    dropped.store(true, Ordering::SeqCst);
+}
+
+// the client's end of its buffer, ends with a resync if the client was dropped
+struct Receiver {
+    rx: mpsc::Receiver<Frame>,
+    dropped: Arc<AtomicBool>,
+}
 
-        Some(Client(rx))
+impl Stream for Receiver {
+    type Item = Frame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.rx).poll_next(cx) {
+            Poll::Ready(None) if self.dropped.swap(false, Ordering::SeqCst) => {
+                Poll::Ready(Some(Event::Resync.to_frame()))
+            }
+            poll => poll,
+        }
     }
+}
 
-    pub fn send(&self, thread_id: i32, event: Event) {
-        if let Some(subscribers) = self.0.get(&thread_id) {
-            for subscriber in subscribers.iter() {
-                subscriber.clone().try_send(event.clone().to_message());
+// the last subscriber to leave takes the topic with it, threads with history keep
+// theirs for a while
+struct Subscription {
+    topics: Arc<DashMap<Channel, Topic>>,
+    channel: Channel,
+    cancel: Option<oneshot::Sender<()>>,
+    addresses: Arc<DashMap<String, usize>>,
+    address: String,
+    counters: Arc<Counters>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+        if let Some(mut topic) = self.topics.get_mut(&self.channel) {
+            topic.subscribers -= 1;
+            if topic.subscribers == 0 {
+                topic.idle_since = Some(Instant::now());
             }
         }
+        // a thread with no history has nothing worth keeping, that's also every thread
+        // id that was made up, they're joined before anyone checks that they exist
+        self.topics.remove_if(&self.channel, |channel, topic| {
+            topic.subscribers == 0
+                && (topic.history.is_empty() || !matches!(channel, Channel::Thread(_)))
+        });
+
+        if let Some(mut count) = self.addresses.get_mut(&self.address) {
+            *count -= 1;
+        }
+        self.addresses
+            .remove_if(&self.address, |_, count| *count == 0);
+        self.counters.subscribers.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
-pub struct Client(Receiver<Bytes>);
+pub struct Client {
+    rx: Receiver,
+    _subscription: Subscription,
+}
 
 impl Stream for Client {
     type Item = Result<Bytes, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match Pin::new(&mut self.0).poll_next(cx) {
-            Poll::Ready(Some(v)) => Poll::Ready(Some(Ok(v))),
+        match Pin::new(&mut self.rx).poll_next(cx) {
+            Poll::Ready(Some(frame)) => Poll::Ready(Some(Ok(frame.to_sse()))),
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Pending => Poll::Pending,
         }
     }
 }
+
+// json text messages for a websocket, each one says which channel it came from
+pub struct Frames {
+    channel: Channel,
+    rx: Receiver,
+    ended: bool,
+    _subscription: Subscription,
+}
+
+// one socket reads many of these, so the end of each is an item of its own
+pub enum FrameItem {
+    Text(String),
+    Ended(Channel),
+}
+
+impl Stream for Frames {
+    type Item = FrameItem;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.ended {
+            return Poll::Ready(None);
+        }
+        Pin::new(&mut this.rx)
+            .poll_next(cx)
+            .map(|frame| match frame {
+                Some(frame) => Some(FrameItem::Text(frame.to_json(&this.channel))),
+                None => {
+                    this.ended = true;
+                    Some(FrameItem::Ended(this.channel.clone()))
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broadcaster() -> Broadcaster {
+        Broadcaster {
+            topics: Arc::new(DashMap::new()),
+            addresses: Arc::new(DashMap::new()),
+            counters: Arc::new(Counters::default()),
+            closing: AtomicBool::new(false),
+        }
+    }
+
+    fn reader() -> Viewer {
+        Viewer {
+            identity: Some("reader".to_owned()),
+            staff: false,
+        }
+    }
+
+    // the event name and id of the next message, None once nothing more comes
+    async fn next(frames: &mut Frames) -> Option<(String, Option<i64>)> {
+        match timeout(Duration::from_millis(100), frames.next()).await {
+            Ok(Some(FrameItem::Text(text))) => {
+                let message: Value = serde_json::from_str(&text).unwrap();
+                Some((
+                    message["event"].as_str().unwrap().to_owned(),
+                    message["id"].as_i64(),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn posts_sent_while_the_snapshot_loads_arrive_once() {
+        let brd = broadcaster();
+        let thread = Channel::Thread(1);
+        let joined = brd.join_channel(thread.clone(), "127.0.0.1").unwrap();
+
+        // published after joining, the first one before the snapshot was read
+        // and the second one after
+        let in_snapshot = Post::published(1, "author", "first");
+        brd.send(&thread, Event::Post(&in_snapshot));
+        let after_snapshot = Post::published(2, "author", "second");
+        brd.send(&thread, Event::Post(&after_snapshot));
+
+        let snapshot = vec![Event::Ping.to_frame()];
+        let replayed = vec![in_snapshot.id].into_iter().collect();
+        let mut frames = joined.frames(snapshot, replayed, reader());
+        let live = Post::published(3, "author", "third");
+        brd.send(&thread, Event::Post(&live));
+
+        assert_eq!(next(&mut frames).await, Some(("ping".to_owned(), None)));
+        assert_eq!(next(&mut frames).await, Some(("post".to_owned(), Some(2))));
+        assert_eq!(next(&mut frames).await, Some(("post".to_owned(), Some(3))));
+        assert_eq!(next(&mut frames).await, None);
+    }
+
+    #[actix_rt::test]
+    async fn made_up_threads_dont_outlive_their_subscribers() {
+        let brd = broadcaster();
+        let joined = brd.join_channel(Channel::Thread(404), "127.0.0.1").unwrap();
+        drop(joined);
+        assert_eq!(brd.stats().topics, 0);
+        assert_eq!(brd.total_subscribers(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn threads_with_history_stay_after_the_last_subscriber() {
+        let brd = broadcaster();
+        let thread = Channel::Thread(1);
+        let joined = brd.join_channel(thread.clone(), "127.0.0.1").unwrap();
+        brd.send(&thread, Event::Post(&Post::published(1, "author", "first")));
+        drop(joined);
+        assert_eq!(brd.stats().topics, 1);
+        assert_eq!(brd.subscribers(&thread), 0);
+    }
+
+    #[actix_rt::test]
+    async fn deleted_posts_arent_replayed() {
+        let brd = broadcaster();
+        let thread = Channel::Thread(1);
+        let joined = brd.join_channel(thread.clone(), "127.0.0.1").unwrap();
+        for (id, message) in vec![(1, "first"), (2, "second"), (3, "third")] {
+            brd.send(
+                &thread,
+                Event::Post(&Post::published(id, "author", message)),
+            );
+        }
+        brd.send(&thread, Event::Delete(2));
+        drop(joined);
+
+        let (_, replayed) = brd.join_thread(1, Some(1), &reader(), "127.0.0.1").unwrap();
+        let ids: Vec<i64> = replayed.unwrap().iter().map(|post| post.id).collect();
+        assert_eq!(ids, vec![3]);
+    }
+}